@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use log::debug;
+use serde::Deserialize;
+
+use crate::migration_state::Layout;
+use crate::session_variables::SessionValue;
+use crate::version::Versioning;
+
+pub(crate) const CONFIG_FILE_NAME: &str = "rmmm.toml";
+
+/// Settings for one named environment (dev, ci, prod, ...) in rmmm.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Environment {
+    pub database_url: Option<String>,
+    pub database_dsn: Option<String>,
+    /// File holding the database password, such as a mounted secret
+    pub database_password_file: Option<String>,
+    pub migrations_table: Option<String>,
+    pub migration_log_table: Option<String>,
+    pub lock_timeout: Option<u32>,
+    /// MySQL option file to read instead of the usual ones
+    pub defaults_file: Option<String>,
+    pub defaults_group_suffix: Option<String>,
+    /// Whether to write structure.sql after changing the database
+    pub write_schema: Option<bool>,
+    /// Session system variables to set on every connection
+    #[serde(default)]
+    pub session_variables: BTreeMap<String, SessionValue>,
+    /// Set to false to refuse `reset` and `apply-snapshot`
+    #[serde(default = "default_true")]
+    pub allow_reset: bool,
+    /// Set to false to refuse `downgrade`
+    #[serde(default = "default_true")]
+    pub allow_downgrade: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The contents of rmmm.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    pub migration_path: Option<String>,
+    /// How `generate` numbers new migrations
+    #[serde(default)]
+    pub versioning: Versioning,
+    /// Whether `generate` writes downgrades to their own files
+    #[serde(default)]
+    pub layout: Layout,
+    /// Environment to use when `--env` isn't passed
+    pub default_env: Option<String>,
+    #[serde(default)]
+    pub environments: BTreeMap<String, Environment>,
+}
+
+impl Config {
+    fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Could not parse {}", path.display()))
+    }
+
+    /// Load the config file passed with `--config`, or else look for rmmm.toml in the working
+    /// directory and then in the migration path. Having no config file at all is fine.
+    pub fn load(matches: &clap::ArgMatches) -> anyhow::Result<Self> {
+        if let Some(path) = matches.value_of("config") {
+            return Self::from_path(Path::new(path));
+        }
+        let candidates = [
+            PathBuf::from(CONFIG_FILE_NAME),
+            Path::new(matches.value_of("migration_path").unwrap()).join(CONFIG_FILE_NAME),
+        ];
+        for candidate in candidates {
+            if candidate.exists() {
+                debug!("Loading configuration from {candidate:?}");
+                return Self::from_path(&candidate);
+            }
+        }
+        Ok(Config::default())
+    }
+
+    /// Pick the environment named by `--env`, or the default one. If neither is set, all
+    /// settings come from the command line and environment variables.
+    pub fn into_environment(mut self, matches: &clap::ArgMatches) -> anyhow::Result<Environment> {
+        let name = match matches.value_of("env") {
+            Some(name) => name.to_string(),
+            None => match self.default_env.take() {
+                Some(name) => name,
+                None => return Ok(Environment::default()),
+            },
+        };
+        let available = self.environments.keys().cloned().collect::<Vec<_>>();
+        self.environments.remove(&name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "no environment {:?} in {}; available environments are {:?}",
+                name,
+                CONFIG_FILE_NAME,
+                available
+            )
+        })
+    }
+}
+
+/// Whether the argument was given on the command line or through its environment variable, as
+/// opposed to being left at its default
+fn is_explicit(matches: &clap::ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Look up an argument which might also be set in rmmm.toml. The command line comes first, then
+/// environment variables, then the config file, then the argument's default.
+pub(crate) fn lookup(
+    matches: &clap::ArgMatches,
+    id: &str,
+    configured: Option<String>,
+) -> Option<String> {
+    if is_explicit(matches, id) {
+        return matches.value_of(id).map(str::to_owned);
+    }
+    configured.or_else(|| matches.value_of(id).map(str::to_owned))
+}
+
+/// Like [lookup], but for flags
+pub(crate) fn lookup_flag(matches: &clap::ArgMatches, id: &str, configured: Option<bool>) -> bool {
+    if is_explicit(matches, id) {
+        return true;
+    }
+    configured.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, lookup, lookup_flag};
+    use crate::cli;
+    use crate::migration_state::Layout;
+    use crate::session_variables::SessionValue;
+    use crate::version::Versioning;
+
+    const EXAMPLE: &str = r#"
+        default_env = "dev"
+        versioning = "timestamp"
+        layout = "single-file"
+
+        [environments.dev]
+        database_url = "mysql://root@localhost/app_dev"
+
+        [environments.prod]
+        database_dsn = "app@tcp(db.internal)/app"
+        migrations_table = "meta.app_migrations"
+        lock_timeout = 300
+        write_schema = false
+        allow_reset = false
+        allow_downgrade = false
+
+        [environments.prod.session_variables]
+        sql_mode = "STRICT_ALL_TABLES,NO_ENGINE_SUBSTITUTION"
+        time_zone = "+00:00"
+        foreign_key_checks = true
+        lock_wait_timeout = 10
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        assert_eq!(config.default_env.as_deref(), Some("dev"));
+        assert_eq!(config.versioning, Versioning::Timestamp);
+        assert_eq!(config.layout, Layout::SingleFile);
+        let dev = &config.environments["dev"];
+        assert!(dev.allow_reset);
+        assert_eq!(dev.lock_timeout, None);
+        let prod = &config.environments["prod"];
+        assert!(!prod.allow_reset);
+        assert_eq!(prod.write_schema, Some(false));
+        assert_eq!(
+            prod.session_variables["foreign_key_checks"],
+            SessionValue::Bool(true)
+        );
+        assert_eq!(
+            prod.session_variables["lock_wait_timeout"],
+            SessionValue::Integer(10)
+        );
+        assert_eq!(
+            prod.session_variables["time_zone"],
+            SessionValue::String("+00:00".to_string())
+        );
+        assert!(toml::from_str::<Config>("[environments.dev]\ndatabase_ulr = 'x'").is_err());
+    }
+
+    #[test]
+    fn test_environment_selection() {
+        let matches = cli().get_matches_from(["rmmm", "status"]);
+        let env = toml::from_str::<Config>(EXAMPLE)
+            .unwrap()
+            .into_environment(&matches)
+            .unwrap();
+        assert_eq!(
+            env.database_url.as_deref(),
+            Some("mysql://root@localhost/app_dev")
+        );
+
+        let matches = cli().get_matches_from(["rmmm", "--env", "prod", "status"]);
+        let env = toml::from_str::<Config>(EXAMPLE)
+            .unwrap()
+            .into_environment(&matches)
+            .unwrap();
+        assert_eq!(env.lock_timeout, Some(300));
+
+        let matches = cli().get_matches_from(["rmmm", "--env", "staging", "status"]);
+        assert!(
+            toml::from_str::<Config>(EXAMPLE)
+                .unwrap()
+                .into_environment(&matches)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        let matches = cli().get_matches_from(["rmmm", "--lock-timeout", "5", "status"]);
+        assert_eq!(
+            lookup(&matches, "lock_timeout", Some("300".to_string())).as_deref(),
+            Some("5")
+        );
+        let matches = cli().get_matches_from(["rmmm", "status"]);
+        assert_eq!(
+            lookup(&matches, "lock_timeout", Some("300".to_string())).as_deref(),
+            Some("300")
+        );
+        assert_eq!(
+            lookup(&matches, "lock_timeout", None).as_deref(),
+            Some("60")
+        );
+        assert!(!lookup_flag(&matches, "quiet", None));
+        assert!(lookup_flag(&matches, "quiet", Some(true)));
+    }
+}
@@ -0,0 +1,338 @@
+//! Drafting downgrades for simple migrations. Only statements whose inverse is mechanical (such
+//! as `CREATE TABLE` or `ADD COLUMN`) are reversed; anything else is left for a person to deal
+//! with.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::sql_lexer::{Lexer, TokenKind, split_statements, strip_comments};
+
+/// Starts the comment on each statement which has to be reversed by hand
+pub(crate) const TODO_MARKER: &str = "-- TODO(rmmm):";
+
+// a possibly-quoted identifier, and one which may be qualified with a schema
+const IDENT: &str = r"(?:`(?:[^`]|``)+`|[A-Za-z0-9_$]+)";
+
+fn regex(pattern: &str) -> Regex {
+    let qualified = format!(r"{IDENT}(?:\s*\.\s*{IDENT})?");
+    Regex::new(
+        &format!("(?is)^{pattern}")
+            .replace("IF_NOT_EXISTS", IF_NOT_EXISTS)
+            .replace("QUALIFIED", &qualified)
+            .replace("IDENT", IDENT),
+    )
+    .unwrap()
+}
+
+// CREATE ... IF NOT EXISTS may not have created anything, and CREATE OR REPLACE VIEW may have
+// replaced something, so dropping what they name could lose something which was there before.
+// The former is captured to be turned away; the latter isn't matched.
+const IF_NOT_EXISTS: &str = r"(?P<if_not_exists>IF\s+NOT\s+EXISTS\s+)?";
+
+static CREATE_TABLE: Lazy<Regex> =
+    Lazy::new(|| regex(r"CREATE\s+TABLE\s+IF_NOT_EXISTS(QUALIFIED)"));
+static CREATE_VIEW: Lazy<Regex> = Lazy::new(|| {
+    regex(
+        r"CREATE\s+(?:ALGORITHM\s*=\s*\w+\s+)?(?:DEFINER\s*=\s*\S+\s+)?(?:SQL\s+SECURITY\s+\w+\s+)?VIEW\s+IF_NOT_EXISTS(QUALIFIED)",
+    )
+});
+static CREATE_INDEX: Lazy<Regex> = Lazy::new(|| {
+    regex(
+        r"CREATE\s+(?:UNIQUE\s+|FULLTEXT\s+|SPATIAL\s+)?INDEX\s+IF_NOT_EXISTS(IDENT)\s+(?:USING\s+\w+\s+)?ON\s+(QUALIFIED)",
+    )
+});
+static CREATE_ROUTINE: Lazy<Regex> = Lazy::new(|| {
+    regex(
+        r"CREATE\s+(?:DEFINER\s*=\s*\S+\s+)?(TRIGGER|PROCEDURE|FUNCTION)\s+IF_NOT_EXISTS(QUALIFIED)",
+    )
+});
+static ALTER_TABLE: Lazy<Regex> = Lazy::new(|| regex(r"ALTER\s+TABLE\s+(QUALIFIED)\s+(.*)$"));
+static RENAME_TABLE: Lazy<Regex> = Lazy::new(|| regex(r"RENAME\s+TABLES?\s+(.*)$"));
+static RENAME_PAIR: Lazy<Regex> = Lazy::new(|| regex(r"(QUALIFIED)\s+TO\s+(QUALIFIED)$"));
+
+// clauses of ALTER TABLE
+static CLAUSE_IF_NOT_EXISTS: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\bIF\s+NOT\s+EXISTS\b").unwrap());
+static TABLE_OPTION: Lazy<Regex> = Lazy::new(|| regex(r"(?:ALGORITHM|LOCK)\s*=?\s*\w+$"));
+static ADD_PRIMARY_KEY: Lazy<Regex> =
+    Lazy::new(|| regex(r"ADD\s+(?:CONSTRAINT\s+(?:IDENT\s+)?)?PRIMARY\s+KEY"));
+static ADD_FOREIGN_KEY: Lazy<Regex> =
+    Lazy::new(|| regex(r"ADD\s+CONSTRAINT\s+(IDENT)\s+FOREIGN\s+KEY"));
+static ADD_CHECK: Lazy<Regex> = Lazy::new(|| regex(r"ADD\s+CONSTRAINT\s+(IDENT)\s+CHECK"));
+static ADD_UNIQUE: Lazy<Regex> = Lazy::new(|| {
+    regex(
+        r"ADD\s+(?:CONSTRAINT\s+(IDENT)\s+)?UNIQUE(?:\s+(?:INDEX|KEY))?(?:\s+(IDENT))?\s*(?:USING\s+\w+\s*)?\(",
+    )
+});
+static ADD_INDEX: Lazy<Regex> = Lazy::new(|| {
+    regex(r"ADD\s+(?:FULLTEXT\s+|SPATIAL\s+)?(?:INDEX|KEY)\s+(IDENT)\s*(?:USING\s+\w+\s*)?\(")
+});
+static ADD_COLUMN: Lazy<Regex> = Lazy::new(|| regex(r"ADD\s+(COLUMN\s+)?(IDENT)\s"));
+static RENAME_COLUMN: Lazy<Regex> =
+    Lazy::new(|| regex(r"RENAME\s+COLUMN\s+(IDENT)\s+TO\s+(IDENT)$"));
+static RENAME_INDEX: Lazy<Regex> =
+    Lazy::new(|| regex(r"RENAME\s+(?:INDEX|KEY)\s+(IDENT)\s+TO\s+(IDENT)$"));
+
+/// Words which can follow `ADD` in `ALTER TABLE` without being a column name
+const NOT_COLUMNS: &[&str] = &[
+    "CHECK",
+    "COLUMN",
+    "CONSTRAINT",
+    "FOREIGN",
+    "FULLTEXT",
+    "INDEX",
+    "KEY",
+    "PARTITION",
+    "PRIMARY",
+    "SPATIAL",
+    "UNIQUE",
+];
+
+/// A draft downgrade
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Suggestion {
+    pub sql: String,
+    /// How many statements have to be reversed by hand
+    pub todo: usize,
+}
+
+/// Split `s` on commas which aren't inside parentheses, strings or quoted identifiers
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut start) = (0usize, 0);
+    for token in Lexer::new(s).flatten() {
+        if token.kind != TokenKind::Text {
+            continue;
+        }
+        for (i, c) in token.text.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    parts.push(s[start..token.offset + i].trim());
+                    start = token.offset + i + 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+/// The inverse of one clause of `ALTER TABLE`, or `None` if it can't be worked out. Table
+/// options such as `ALGORITHM=INPLACE` have no inverse, but don't need one either.
+fn reverse_alter_clause(clause: &str) -> Option<Option<String>> {
+    if TABLE_OPTION.is_match(clause) {
+        return Some(None);
+    }
+    if CLAUSE_IF_NOT_EXISTS.is_match(clause) {
+        return None;
+    }
+    let reversed = if ADD_PRIMARY_KEY.is_match(clause) {
+        "DROP PRIMARY KEY".to_string()
+    } else if let Some(caps) = ADD_FOREIGN_KEY.captures(clause) {
+        format!("DROP FOREIGN KEY {}", &caps[1])
+    } else if let Some(caps) = ADD_CHECK.captures(clause) {
+        format!("DROP CHECK {}", &caps[1])
+    } else if let Some(caps) = ADD_UNIQUE.captures(clause) {
+        // an unnamed unique key is named after its first column, which we'd have to parse out
+        let name = caps.get(2).or(caps.get(1))?.as_str();
+        if ["INDEX", "KEY"]
+            .iter()
+            .any(|k| k.eq_ignore_ascii_case(name))
+        {
+            return None;
+        }
+        format!("DROP INDEX {name}")
+    } else if let Some(caps) = ADD_INDEX.captures(clause) {
+        format!("DROP INDEX {}", &caps[1])
+    } else if let Some(caps) = ADD_COLUMN.captures(clause) {
+        let name = &caps[2];
+        if caps.get(1).is_none() && NOT_COLUMNS.iter().any(|k| k.eq_ignore_ascii_case(name)) {
+            return None;
+        }
+        format!("DROP COLUMN {name}")
+    } else if let Some(caps) = RENAME_COLUMN.captures(clause) {
+        format!("RENAME COLUMN {} TO {}", &caps[2], &caps[1])
+    } else if let Some(caps) = RENAME_INDEX.captures(clause) {
+        format!("RENAME INDEX {} TO {}", &caps[2], &caps[1])
+    } else {
+        return None;
+    };
+    Some(Some(reversed))
+}
+
+/// The statement undoing `statement`, if it can be worked out
+fn reverse_statement(statement: &str) -> Option<String> {
+    let statement = statement.trim();
+    let creates = [&CREATE_TABLE, &CREATE_VIEW, &CREATE_INDEX, &CREATE_ROUTINE];
+    if creates
+        .iter()
+        .filter_map(|r| r.captures(statement))
+        .any(|caps| caps.name("if_not_exists").is_some())
+    {
+        return None;
+    }
+    if let Some(caps) = CREATE_TABLE.captures(statement) {
+        Some(format!("DROP TABLE {}", &caps[2]))
+    } else if let Some(caps) = CREATE_VIEW.captures(statement) {
+        Some(format!("DROP VIEW {}", &caps[2]))
+    } else if let Some(caps) = CREATE_INDEX.captures(statement) {
+        Some(format!("DROP INDEX {} ON {}", &caps[2], &caps[3]))
+    } else if let Some(caps) = CREATE_ROUTINE.captures(statement) {
+        Some(format!(
+            "DROP {} {}",
+            caps[1].to_ascii_uppercase(),
+            &caps[3]
+        ))
+    } else if let Some(caps) = ALTER_TABLE.captures(statement) {
+        let clauses = split_top_level(&caps[2])
+            .into_iter()
+            .map(reverse_alter_clause)
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .rev()
+            .collect::<Vec<_>>();
+        if clauses.is_empty() {
+            return None;
+        }
+        Some(format!("ALTER TABLE {} {}", &caps[1], clauses.join(", ")))
+    } else if let Some(caps) = RENAME_TABLE.captures(statement) {
+        let pairs = split_top_level(&caps[1])
+            .into_iter()
+            .map(|pair| {
+                RENAME_PAIR
+                    .captures(pair)
+                    .map(|p| format!("{} TO {}", &p[2], &p[1]))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(format!(
+            "RENAME TABLE {}",
+            pairs.into_iter().rev().collect::<Vec<_>>().join(", ")
+        ))
+    } else {
+        None
+    }
+}
+
+/// Draft a downgrade for `upgrade`, undoing its statements in reverse order. Statements which
+/// can't be reversed are copied into comments starting with [TODO_MARKER].
+pub(crate) fn suggest_downgrade(upgrade: &str) -> anyhow::Result<Suggestion> {
+    let upgrade = strip_comments(upgrade)?;
+    let mut lines = vec![];
+    let mut todo = 0;
+    for statement in split_statements(&upgrade)?.iter().rev() {
+        match reverse_statement(statement.text) {
+            Some(reversed) => lines.push(format!("{reversed};")),
+            None => {
+                todo += 1;
+                lines.push(format!(
+                    "{TODO_MARKER} reverse this statement from line {} by hand",
+                    statement.line
+                ));
+                lines.extend(statement.text.lines().map(|l| format!("-- {l}")));
+            }
+        }
+    }
+    Ok(Suggestion {
+        sql: lines.join("\n"),
+        todo,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_statement, split_top_level, suggest_downgrade};
+
+    #[test]
+    fn test_split_top_level() {
+        assert_eq!(
+            split_top_level("ADD COLUMN a DECIMAL(10, 2) DEFAULT ',', ADD INDEX idx (a, b)"),
+            vec![
+                "ADD COLUMN a DECIMAL(10, 2) DEFAULT ','",
+                "ADD INDEX idx (a, b)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reverse_statement() {
+        for (statement, expected) in [
+            (
+                "CREATE TABLE `app`.`users` (id INT PRIMARY KEY)",
+                Some("DROP TABLE `app`.`users`"),
+            ),
+            (
+                "CREATE TABLE IF NOT EXISTS `app`.`users` (id INT PRIMARY KEY)",
+                None,
+            ),
+            ("CREATE PROCEDURE IF NOT EXISTS p() SELECT 1", None),
+            (
+                "create view active_users as select * from users",
+                Some("DROP VIEW active_users"),
+            ),
+            ("CREATE OR REPLACE VIEW v AS SELECT 1", None),
+            (
+                "CREATE UNIQUE INDEX idx_email ON users (email)",
+                Some("DROP INDEX idx_email ON users"),
+            ),
+            (
+                "CREATE DEFINER=`app`@`%` PROCEDURE cleanup() BEGIN DELETE FROM t; END",
+                Some("DROP PROCEDURE cleanup"),
+            ),
+            (
+                "ALTER TABLE users ADD COLUMN age INT NOT NULL DEFAULT 0, ADD INDEX idx_age (age), ALGORITHM=INPLACE, LOCK=NONE",
+                Some("ALTER TABLE users DROP INDEX idx_age, DROP COLUMN age"),
+            ),
+            (
+                "ALTER TABLE orders ADD CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES users (id), ADD UNIQUE KEY uniq_ref (ref), ADD `note` TEXT",
+                Some(
+                    "ALTER TABLE orders DROP COLUMN `note`, DROP INDEX uniq_ref, DROP FOREIGN KEY fk_user",
+                ),
+            ),
+            (
+                "ALTER TABLE users RENAME COLUMN name TO full_name",
+                Some("ALTER TABLE users RENAME COLUMN full_name TO name"),
+            ),
+            (
+                "RENAME TABLE a TO b, c TO d",
+                Some("RENAME TABLE d TO c, b TO a"),
+            ),
+            ("ALTER TABLE users DROP COLUMN age", None),
+            ("ALTER TABLE users ADD INDEX (age)", None),
+            ("ALTER TABLE users ADD UNIQUE KEY(email)", None),
+            (
+                "ALTER TABLE users ADD UNIQUE KEY uniq_email(email)",
+                Some("ALTER TABLE users DROP INDEX uniq_email"),
+            ),
+            ("ALTER TABLE users ADD COLUMN IF NOT EXISTS age INT", None),
+            ("ALTER TABLE users ADD COLUMN a INT, MODIFY b BIGINT", None),
+            ("UPDATE users SET age = 0", None),
+        ] {
+            assert_eq!(
+                reverse_statement(statement).as_deref(),
+                expected,
+                "{statement}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_suggest_downgrade() {
+        let suggestion = suggest_downgrade(
+            "CREATE TABLE a (id INT);\n-- a comment\nUPDATE b\n  SET x = 1;\nALTER TABLE b ADD COLUMN y INT;\n",
+        )
+        .unwrap();
+        assert_eq!(suggestion.todo, 1);
+        assert_eq!(
+            suggestion.sql,
+            "ALTER TABLE b DROP COLUMN y;\n\
+            -- TODO(rmmm): reverse this statement from line 3 by hand\n\
+            -- UPDATE b\n\
+            --   SET x = 1\n\
+            DROP TABLE a;"
+        );
+    }
+}
@@ -2,20 +2,42 @@
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::io::Read;
+use std::process::ExitCode;
 
 use anyhow::Context;
 use clap::Arg;
 use derive_more::Display;
 use itertools::Itertools;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use mysql::prelude::Queryable;
+use serde::Serialize;
 use tabled::Tabled;
 
+mod config;
+mod downgrade;
 mod go_database_dsn;
 mod migration_runner;
 mod migration_state;
+mod option_file;
+mod output;
+mod password;
+mod session_variables;
+mod sql_lexer;
+mod table_name;
+mod template;
+mod version;
 
-use crate::migration_runner::MigrationRunner;
-use crate::migration_state::MigrationState;
+use crate::config::{CONFIG_FILE_NAME, Config, Environment, lookup, lookup_flag};
+use crate::go_database_dsn::{GoDatabaseDsn, ParamOutcome};
+use crate::migration_runner::{
+    ExecutedMigration, HistoryFilter, LogEntry, MigrationPlan, MigrationRunner, MigrationStep,
+    Operation,
+};
+use crate::migration_state::{Layout, Migration, MigrationState, NewMigration};
+use crate::output::OutputFormat;
+use crate::sql_lexer::split_statements;
+use crate::version::{Version, Versioning};
 
 fn initialize_logging(matches: &clap::ArgMatches) {
     let log_level = match (
@@ -52,76 +74,487 @@ fn initialize_logging(matches: &clap::ArgMatches) {
         .expect("Could not initialize logging");
 }
 
-#[derive(Debug, Display, PartialEq, Eq)]
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 enum MigrationStatus {
     Executed,
     NotExecuted,
+    Dirty,
+    /// Applied, but the file is gone
+    Missing,
+    /// Not applied, though a later migration has been
+    OutOfOrder,
+}
+
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChecksumStatus {
+    Matches,
+    Modified,
+    // applied before checksums were recorded
+    Unrecorded,
+    #[display(fmt = "")]
+    NotApplicable,
+}
+
+impl ChecksumStatus {
+    fn of(migration: Option<&Migration>, executed: Option<&ExecutedMigration>) -> Self {
+        match (migration, executed.map(|e| e.checksum.as_deref())) {
+            (Some(_), Some(None)) => ChecksumStatus::Unrecorded,
+            (Some(m), Some(Some(c))) if m.checksum() == c => ChecksumStatus::Matches,
+            (Some(_), Some(Some(_))) => ChecksumStatus::Modified,
+            _ => ChecksumStatus::NotApplicable,
+        }
+    }
+}
+
+/// IDs of applied migrations whose file no longer matches the recorded checksum
+fn modified_migrations(state: &MigrationState, run_so_far: &[ExecutedMigration]) -> Vec<Version> {
+    let migrations_by_id = state.migrations_by_id();
+    run_so_far
+        .iter()
+        .filter(|r| {
+            ChecksumStatus::of(migrations_by_id.get(&r.id).copied(), Some(r))
+                == ChecksumStatus::Modified
+        })
+        .map(|r| r.id)
+        .sorted()
+        .collect()
+}
+
+/// The overall state of the schema, as reported by `status --check`. When several apply, the
+/// first one listed here wins.
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SchemaState {
+    #[display(fmt = "a migration is dirty")]
+    Dirty,
+    #[display(fmt = "the database has applied migrations which don't exist on disk")]
+    UnknownApplied,
+    #[display(fmt = "applied migrations have been modified since they were run")]
+    ChecksumDrift,
+    #[display(fmt = "there are migrations to apply")]
+    Pending,
+    #[display(fmt = "up to date")]
+    UpToDate,
+}
+
+impl SchemaState {
+    fn of(rows: &[MigrationStatusRow], known_ids: &BTreeSet<Version>) -> Self {
+        if rows.iter().any(|r| r.status == MigrationStatus::Dirty) {
+            SchemaState::Dirty
+        } else if rows
+            .iter()
+            .any(|r| r.status == MigrationStatus::Missing || !known_ids.contains(&r.id))
+        {
+            SchemaState::UnknownApplied
+        } else if rows.iter().any(|r| r.checksum == ChecksumStatus::Modified) {
+            SchemaState::ChecksumDrift
+        } else if rows.iter().any(|r| {
+            matches!(
+                r.status,
+                MigrationStatus::NotExecuted | MigrationStatus::OutOfOrder
+            )
+        }) {
+            SchemaState::Pending
+        } else {
+            SchemaState::UpToDate
+        }
+    }
+
+    /// 1 and 2 are left for errors and bad arguments
+    fn exit_code(self) -> u8 {
+        match self {
+            SchemaState::UpToDate => 0,
+            SchemaState::Pending => 3,
+            SchemaState::UnknownApplied => 4,
+            SchemaState::ChecksumDrift => 5,
+            SchemaState::Dirty => 6,
+        }
+    }
 }
 
 #[derive(Tabled, Debug)]
 struct MigrationStatusRow {
-    id: u32,
+    id: Version,
     label: String,
     status: MigrationStatus,
     executed_at: String,
+    checksum: ChecksumStatus,
+    // the rest are only shown with --details
+    duration: String,
+    applied_by: String,
+    host: String,
+    rmmm_version: String,
+}
+
+#[derive(Tabled)]
+struct SessionVariableRow {
+    #[header("session variable")]
+    name: String,
+    value: String,
 }
 
-fn command_status(state: MigrationState, runner: MigrationRunner) -> anyhow::Result<()> {
+const STATUS_DETAIL_COLUMNS: std::ops::RangeFrom<usize> = 5..;
+
+#[derive(Serialize)]
+struct DirtyJson {
+    direction: &'static str,
+    failed_statement: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MigrationStatusJson {
+    id: Version,
+    label: Option<String>,
+    status: MigrationStatus,
+    executed_at: Option<String>,
+    /// null if the migration hasn't been applied, or its file is missing
+    checksum: Option<ChecksumStatus>,
+    dirty: Option<DirtyJson>,
+    duration_ms: Option<u64>,
+    applied_by: Option<String>,
+    host: Option<String>,
+    rmmm_version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatusJson {
+    state: SchemaState,
+    migrations: Vec<MigrationStatusJson>,
+    /// Problems with the migrations directory, such as gaps in the numbering
+    warnings: Vec<String>,
+    /// Only with --verbose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_variables: Option<BTreeMap<String, Option<String>>>,
+}
+
+fn command_generate(
+    matches: &clap::ArgMatches,
+    state: &MigrationState,
+    versioning: Versioning,
+    layout: Layout,
+) -> anyhow::Result<()> {
+    debug!("Starting command_generate");
+    let read = |path: &str| {
+        std::fs::read_to_string(path).with_context(|| format!("Could not read {path}"))
+    };
+    let upgrade_sql = if matches.is_present("from-stdin") {
+        let mut sql = String::new();
+        std::io::stdin()
+            .read_to_string(&mut sql)
+            .context("Could not read the migration from stdin")?;
+        Some(sql)
+    } else {
+        matches.value_of("sql-file").map(read).transpose()?
+    };
+    let new = NewMigration {
+        label: matches.value_of("label").unwrap().to_string(),
+        versioning,
+        layout,
+        upgrade_sql,
+        downgrade_sql: matches.value_of("downgrade-file").map(read).transpose()?,
+        template: matches
+            .value_of("template")
+            .map(|name| state.template(name))
+            .transpose()?,
+        variables: matches
+            .values_of("var")
+            .into_iter()
+            .flatten()
+            .map(template::parse_variable)
+            .collect::<anyhow::Result<_>>()?,
+        // an editor can't share stdin with the migration
+        edit: !matches.is_present("no-edit") && !matches.is_present("from-stdin"),
+        suggest_downgrade: matches.is_present("with-downgrade"),
+    };
+    for path in state.generate(&new)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn command_status(
+    matches: &clap::ArgMatches,
+    state: MigrationState,
+    runner: MigrationRunner,
+) -> anyhow::Result<ExitCode> {
     debug!("Starting command_status");
+    let output = OutputFormat::from_matches(matches);
     let run_so_far = runner.list_run_migrations()?;
     let all_ids = state
         .all_ids()
-        .union(&run_so_far.iter().map(|m| m.id).collect::<BTreeSet<u32>>())
+        .union(
+            &run_so_far
+                .iter()
+                .map(|m| m.id)
+                .collect::<BTreeSet<Version>>(),
+        )
         .cloned()
-        .collect::<BTreeSet<u32>>();
+        .collect::<BTreeSet<Version>>();
     let migrations_by_id = state.migrations_by_id();
+    let out_of_order = migration_runner::out_of_order(&state, &run_so_far);
     let run_so_far_by_id = run_so_far
         .into_iter()
         .map(|m| (m.id, m))
         .collect::<BTreeMap<_, _>>();
-    let data = all_ids
+    let (data, json): (Vec<_>, Vec<_>) = all_ids
         .into_iter()
         .sorted()
         .map(|id| {
-            let label = if let Some(l) = migrations_by_id.get(&id).and_then(|r| r.label.as_ref()) {
-                l
-            } else {
-                "unknown"
+            let executed = run_so_far_by_id.get(&id);
+            // fall back on the label recorded when the migration was applied
+            let known_label = match migrations_by_id.get(&id) {
+                Some(m) => m.label.clone(),
+                None => executed.map(|r| r.label.clone()).filter(|l| !l.is_empty()),
             };
-            let executed_at = run_so_far_by_id
-                .get(&id)
+            let label = known_label.as_deref().unwrap_or("unknown");
+            let executed_at = executed
                 .map(|r| r.executed_at.map_or("".to_string(), |ea| ea.to_rfc3339()))
                 .unwrap_or_else(|| "".to_string());
-            MigrationStatusRow {
+            let detail = |f: fn(&ExecutedMigration) -> Option<String>| {
+                executed.and_then(f).unwrap_or_default()
+            };
+            let row = MigrationStatusRow {
                 id,
                 executed_at,
-                status: if run_so_far_by_id.contains_key(&id) {
-                    MigrationStatus::Executed
-                } else {
-                    MigrationStatus::NotExecuted
+                duration: detail(|r| r.duration.map(|d| format!("{:.3}s", d.as_secs_f64()))),
+                applied_by: detail(|r| r.applied_by.clone()),
+                host: detail(|r| r.host.clone()),
+                rmmm_version: detail(|r| r.rmmm_version.clone()),
+                checksum: ChecksumStatus::of(
+                    migrations_by_id.get(&id).copied(),
+                    run_so_far_by_id.get(&id),
+                ),
+                status: match executed {
+                    Some(r) if r.dirty.is_some() => MigrationStatus::Dirty,
+                    Some(_) if !migrations_by_id.contains_key(&id) => MigrationStatus::Missing,
+                    Some(_) => MigrationStatus::Executed,
+                    None if out_of_order.contains(&id) => MigrationStatus::OutOfOrder,
+                    None => MigrationStatus::NotExecuted,
                 },
                 label: label.to_string(),
-            }
+            };
+            let json = MigrationStatusJson {
+                id,
+                label: known_label,
+                status: row.status,
+                executed_at: executed.and_then(|r| r.executed_at.map(|t| t.to_rfc3339())),
+                checksum: Some(row.checksum).filter(|c| *c != ChecksumStatus::NotApplicable),
+                dirty: executed.and_then(|r| r.dirty.as_ref()).map(|d| DirtyJson {
+                    direction: if d.is_upgrade { "upgrade" } else { "downgrade" },
+                    failed_statement: d.failed_statement,
+                }),
+                duration_ms: executed.and_then(|r| r.duration.map(|d| d.as_millis() as u64)),
+                applied_by: executed.and_then(|r| r.applied_by.clone()),
+                host: executed.and_then(|r| r.host.clone()),
+                rmmm_version: executed.and_then(|r| r.rmmm_version.clone()),
+            };
+            (row, json)
         })
-        .collect::<Vec<_>>();
-    let table = tabled::Table::new(&data).with(tabled::Style::modern().horizontal_off());
-    println!("{table}");
+        .unzip();
+    let schema_state = SchemaState::of(&data, &state.all_ids());
+    let session_variables = if matches.is_present("verbose") {
+        Some(runner.effective_session_variables()?)
+    } else {
+        None
+    };
+    match output {
+        OutputFormat::Json => output::print_json(
+            "status",
+            &StatusJson {
+                state: schema_state,
+                migrations: json,
+                warnings: state.warnings.iter().map(ToString::to_string).collect(),
+                session_variables: session_variables.map(|v| v.into_iter().collect()),
+            },
+        )?,
+        OutputFormat::Plain => {
+            let columns = if matches.is_present("details") {
+                MigrationStatusRow::LENGTH
+            } else {
+                STATUS_DETAIL_COLUMNS.start
+            };
+            output::print_plain(&data, columns);
+        }
+        OutputFormat::Table => {
+            let mut table =
+                tabled::Table::new(&data).with(tabled::Style::modern().horizontal_off());
+            if !matches.is_present("details") {
+                table = table.with(tabled::Disable::Column(STATUS_DETAIL_COLUMNS));
+            }
+            println!("{table}");
+            if let Some(variables) = session_variables {
+                let variables = variables
+                    .into_iter()
+                    .map(|(name, value)| SessionVariableRow {
+                        name,
+                        value: value.unwrap_or_else(|| "NULL".to_string()),
+                    })
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}",
+                    tabled::Table::new(variables).with(tabled::Style::modern().horizontal_off())
+                );
+            }
+        }
+    }
+    if data.iter().any(|r| r.checksum == ChecksumStatus::Modified) {
+        error!(
+            "some applied migrations have been modified since they were run; run `rmmm rehash` if this was intentional"
+        );
+    }
+    for r in run_so_far_by_id.values() {
+        if let Some(dirty) = &r.dirty {
+            error!("migration {} is dirty: {dirty}", r.id);
+        }
+    }
+    if matches.is_present("check") {
+        info!("schema state: {schema_state}");
+        return Ok(ExitCode::from(schema_state.exit_code()));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Refuse to go any further while a migration is half-applied
+fn check_not_dirty(run_so_far: &[ExecutedMigration]) -> anyhow::Result<()> {
+    if let Some(r) = run_so_far.iter().find(|r| r.dirty.is_some()) {
+        anyhow::bail!(
+            "migration {0} is dirty ({1}); fix the problem and run `rmmm retry {0}`, or `rmmm resolve {0}` if it was fixed by hand",
+            r.id,
+            r.dirty.as_ref().unwrap()
+        );
+    }
+    Ok(())
+}
+
+fn write_schema_unless_disabled(
+    matches: &clap::ArgMatches,
+    env: &Environment,
+    state: &MigrationState,
+    runner: &MigrationRunner,
+) -> anyhow::Result<()> {
+    if !lookup_flag(matches, "no-dump", env.write_schema.map(|w| !w)) {
+        let schema = runner.dump_schema()?;
+        state.write_schema(&schema)?;
+    } else {
+        info!("not writing schema file");
+    }
     Ok(())
 }
 
 #[derive(Tabled, Debug)]
 struct MigrationPlanRow {
-    id: u32,
+    id: Version,
     sql_text: String,
 }
 
+#[derive(Serialize)]
+struct PlanStepJson<'a> {
+    id: Version,
+    label: Option<&'a str>,
+    sql: &'a str,
+}
+
+#[derive(Serialize)]
+struct PlanJson<'a> {
+    direction: &'static str,
+    target: Version,
+    /// false for a dry run
+    executed: bool,
+    steps: Vec<PlanStepJson<'a>>,
+}
+
+fn print_plan_json(
+    plan: &MigrationPlan,
+    is_upgrade: bool,
+    target: Version,
+    executed: bool,
+) -> anyhow::Result<()> {
+    output::print_json(
+        "plan",
+        &PlanJson {
+            direction: if is_upgrade { "upgrade" } else { "downgrade" },
+            target,
+            executed,
+            steps: plan
+                .steps()
+                .iter()
+                .map(|s| PlanStepJson {
+                    id: s.id,
+                    label: s.label.as_deref(),
+                    sql: s.sql.trim(),
+                })
+                .collect(),
+        },
+    )
+}
+
+fn print_plan(plan: &MigrationPlan, output: OutputFormat) {
+    let plan_data = plan
+        .steps()
+        .iter()
+        .map(|ps| MigrationPlanRow {
+            id: ps.id,
+            sql_text: ps.sql.trim().to_string(),
+        })
+        .collect::<Vec<_>>();
+    let table = tabled::Table::new(&plan_data)
+        .with(tabled::Style::modern().horizontal_off())
+        .with(tabled::Modify::new(tabled::Column(1..=1)).with(tabled::Alignment::left()));
+    match output {
+        OutputFormat::Plain => output::print_plain(&plan_data, MigrationPlanRow::LENGTH),
+        // JSON is printed once the plan has been carried out (or not)
+        OutputFormat::Json => {}
+        OutputFormat::Table => {
+            println!("Migration plan:");
+            println!("{table}");
+        }
+    }
+}
+
+/// Plan an upgrade or downgrade from the migrations applied right now, refusing if one of them
+/// is dirty or (when upgrading) has been modified
+fn checked_plan(
+    matches: &clap::ArgMatches,
+    state: &MigrationState,
+    runner: &MigrationRunner,
+    target_revision: Version,
+    is_upgrade: bool,
+) -> anyhow::Result<MigrationPlan> {
+    let run_so_far = runner.list_run_migrations()?;
+    check_not_dirty(&run_so_far)?;
+    if !is_upgrade {
+        return MigrationPlan::downgrade(state, &run_so_far, target_revision);
+    }
+    let modified = modified_migrations(state, &run_so_far);
+    if !modified.is_empty() {
+        if matches.is_present("allow-modified") {
+            warn!("applied migrations {modified:?} have been modified since they were run");
+        } else {
+            anyhow::bail!(
+                "applied migrations {:?} have been modified since they were run; pass --allow-modified to upgrade anyway, or run `rmmm rehash` to accept the changes",
+                modified
+            );
+        }
+    }
+    MigrationPlan::upgrade(
+        state,
+        &run_so_far,
+        target_revision,
+        matches.is_present("allow-out-of-order"),
+    )
+}
+
 fn command_apply_migrations(
     matches: &clap::ArgMatches,
+    env: &Environment,
     state: MigrationState,
     runner: MigrationRunner,
     is_upgrade: bool,
 ) -> anyhow::Result<()> {
     debug!("Starting command_upgrade");
+    let output = OutputFormat::from_matches(matches);
     let target_revision = {
         let revision = matches.value_of("revision").unwrap();
         if revision == "latest" {
@@ -132,58 +565,417 @@ fn command_apply_migrations(
                 .context("revision must be an integer or 'latest'")?
         }
     };
-    let plan = runner.plan(&state, target_revision, is_upgrade)?;
+    // the plan is only made once the lock is held, so that it reflects whatever the previous
+    // holder did, including leaving a migration dirty
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    let plan = checked_plan(matches, &state, &runner, target_revision, is_upgrade)?;
     if plan.is_empty() {
         info!("Nothing to do!");
+        if output == OutputFormat::Json {
+            print_plan_json(&plan, is_upgrade, target_revision, false)?;
+        }
         return Ok(());
     }
-    let plan_data = plan
-        .steps()
-        .iter()
-        .map(|ps| MigrationPlanRow {
-            id: ps.id,
-            sql_text: ps.sql.clone(),
-        })
-        .collect::<Vec<_>>();
-    let table = tabled::Table::new(&plan_data)
-        .with(tabled::Style::modern().horizontal_off())
-        .with(tabled::Modify::new(tabled::Column(1..=1)).with(tabled::Alignment::left()));
-    println!("Migration plan:");
-    println!("{table}");
+    print_plan(&plan, output);
     if matches.is_present("execute") {
         info!("executing plan with {} steps", plan.steps().len());
-        runner.execute(plan)?;
+        runner.execute(&plan)?;
         info!("done!");
-        println!("New version: {target_revision}");
-        if !matches.is_present("no-dump") {
-            let schema = runner.dump_schema()?;
-            state.write_schema(&schema)?;
-        } else {
-            println!("not writing schema file");
+        match output {
+            OutputFormat::Json => print_plan_json(&plan, is_upgrade, target_revision, true)?,
+            OutputFormat::Table => println!("New version: {target_revision}"),
+            OutputFormat::Plain => {}
         }
+        write_schema_unless_disabled(matches, env, &state, &runner)?;
     } else {
+        if output == OutputFormat::Json {
+            print_plan_json(&plan, is_upgrade, target_revision, false)?;
+        }
         error!("rerun with --execute to execute this plan");
     }
     Ok(())
 }
 
+fn command_retry(
+    matches: &clap::ArgMatches,
+    env: &Environment,
+    state: MigrationState,
+    runner: MigrationRunner,
+) -> anyhow::Result<()> {
+    debug!("Starting command_retry");
+    let id: Version = matches
+        .value_of("id")
+        .unwrap()
+        .parse()
+        .context("id must be an integer")?;
+    // the dirty state is only read once the lock is held, so that another run can't have moved
+    // the migration on in the meantime
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    let executed = runner
+        .list_run_migrations()?
+        .into_iter()
+        .find(|r| r.id == id && r.dirty.is_some())
+        .ok_or_else(|| anyhow::anyhow!("migration {} is not dirty", id))?;
+    let dirty = executed.dirty.as_ref().unwrap();
+    let migration = state.migrations_by_id().get(&id).copied();
+    let step = match migration {
+        Some(migration) if dirty.is_upgrade => MigrationStep {
+            id,
+            label: migration.label.clone(),
+            sql: migration.upgrade_text.clone(),
+            checksum: Some(migration.checksum()),
+            downgrade_sql: migration.downgrade_text.clone(),
+        },
+        None if dirty.is_upgrade => {
+            anyhow::bail!("migration {} does not exist on disk", id)
+        }
+        // a downgrade can carry on with the SQL recorded when the migration was applied
+        _ => MigrationStep {
+            id,
+            label: migration
+                .map(|m| m.label.clone())
+                .unwrap_or_else(|| Some(executed.label.clone())),
+            sql: migration
+                .and_then(|m| m.downgrade_text.clone())
+                .or_else(|| executed.downgrade_sql.clone())
+                .ok_or_else(|| anyhow::anyhow!("migration {} has no downgrade", id))?,
+            checksum: None,
+            downgrade_sql: None,
+        },
+    };
+    let first_statement = match matches.value_of("from_statement") {
+        Some(s) => s.parse().context("--from-statement must be an integer")?,
+        None => dirty.failed_statement.ok_or_else(|| {
+            anyhow::anyhow!(
+                "migration {} was interrupted at an unknown statement; pass --from-statement",
+                id
+            )
+        })?,
+    };
+    println!("Retrying migration {id} ({dirty}) from statement index {first_statement}:");
+    for (index, statement) in split_statements(&step.sql)?
+        .into_iter()
+        .enumerate()
+        .skip(first_statement)
+    {
+        println!(" [{index}] {}", statement.text);
+    }
+    if matches.is_present("execute") {
+        runner.execute_step(&step, dirty.is_upgrade, first_statement, Operation::Retry)?;
+        info!("done!");
+        write_schema_unless_disabled(matches, env, &state, &runner)?;
+    } else {
+        error!("rerun with --execute to retry this migration");
+    }
+    Ok(())
+}
+
+fn command_resolve(matches: &clap::ArgMatches, runner: MigrationRunner) -> anyhow::Result<()> {
+    debug!("Starting command_resolve");
+    let id: Version = matches
+        .value_of("id")
+        .unwrap()
+        .parse()
+        .context("id must be an integer")?;
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    let dirty = runner
+        .list_run_migrations()?
+        .into_iter()
+        .find(|r| r.id == id)
+        .and_then(|r| r.dirty)
+        .ok_or_else(|| anyhow::anyhow!("migration {} is not dirty", id))?;
+    let applied = matches.is_present("applied");
+    if applied {
+        println!("Marking migration {id} ({dirty}) as applied");
+    } else {
+        println!("Marking migration {id} ({dirty}) as not applied");
+    }
+    if matches.is_present("execute") {
+        let operation = if applied {
+            Operation::MarkApplied
+        } else {
+            Operation::MarkRolledBack
+        };
+        // the direction is that of the interrupted migration; the operation says how it ended
+        let entry = LogEntry {
+            migration_id: Some(id),
+            is_upgrade: Some(dirty.is_upgrade),
+            ..LogEntry::new(operation)
+        };
+        runner.logged(entry, || runner.resolve(id, applied))?;
+    } else {
+        error!("rerun with --execute to resolve this migration");
+    }
+    Ok(())
+}
+
+fn command_rehash(
+    matches: &clap::ArgMatches,
+    state: MigrationState,
+    runner: MigrationRunner,
+) -> anyhow::Result<()> {
+    debug!("Starting command_rehash");
+    // the checksums to update are only worked out once the lock is held
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    let migrations_by_id = state.migrations_by_id();
+    let to_update = runner
+        .list_run_migrations()?
+        .into_iter()
+        .filter_map(|r| {
+            let migration = migrations_by_id.get(&r.id).copied()?;
+            match ChecksumStatus::of(Some(migration), Some(&r)) {
+                ChecksumStatus::Modified | ChecksumStatus::Unrecorded => Some(migration),
+                _ => None,
+            }
+        })
+        .sorted_by_key(|m| m.id)
+        .collect::<Vec<_>>();
+    if to_update.is_empty() {
+        info!("All checksums are up to date");
+        return Ok(());
+    }
+    println!("Recording new checksums for the following migrations:");
+    for migration in &to_update {
+        println!(" - {}", migration.id);
+    }
+    if matches.is_present("execute") {
+        for migration in to_update {
+            let entry = LogEntry {
+                migration_id: Some(migration.id),
+                label: migration.label.as_deref(),
+                ..LogEntry::new(Operation::Rehash)
+            };
+            runner.logged(entry, || {
+                runner.update_checksum(migration.id, &migration.checksum())
+            })?;
+        }
+    } else {
+        error!("rerun with --execute to record these checksums");
+    }
+    Ok(())
+}
+
+#[derive(Tabled, Debug)]
+struct HistoryRow {
+    id: u64,
+    migration: String,
+    label: String,
+    operation: String,
+    direction: String,
+    started_at: String,
+    duration: String,
+    outcome: String,
+    error: String,
+    applied_by: String,
+    host: String,
+    rmmm_version: String,
+}
+
+/// Parse an RFC 3339 timestamp or a plain date; plain dates are taken to mean the start of the
+/// day, or the end of it if `end_of_day` is set
+fn parse_time(s: &str, end_of_day: bool) -> anyhow::Result<chrono::DateTime<chrono::Utc>> {
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&chrono::Utc));
+    }
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .with_context(|| format!("{s:?} is neither a date (YYYY-MM-DD) nor an RFC 3339 time"))?;
+    let date = if end_of_day {
+        date.succ_opt()
+            .ok_or_else(|| anyhow::anyhow!("date {} out of range", s))?
+    } else {
+        date
+    };
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+#[derive(Serialize)]
+struct HistoryEntryJson {
+    id: u64,
+    migration_id: Option<Version>,
+    label: Option<String>,
+    operation: String,
+    direction: Option<String>,
+    started_at: Option<String>,
+    duration_ms: Option<u64>,
+    succeeded: bool,
+    error: Option<String>,
+    applied_by: Option<String>,
+    host: Option<String>,
+    rmmm_version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HistoryJson {
+    operations: Vec<HistoryEntryJson>,
+}
+
+fn command_history(matches: &clap::ArgMatches, runner: MigrationRunner) -> anyhow::Result<()> {
+    debug!("Starting command_history");
+    let output = OutputFormat::from_matches(matches);
+    let filter = HistoryFilter {
+        migration_id: matches
+            .value_of("id")
+            .map(|id| id.parse().context("--id must be an integer"))
+            .transpose()?,
+        since: matches
+            .value_of("since")
+            .map(|s| parse_time(s, false))
+            .transpose()?,
+        until: matches
+            .value_of("until")
+            .map(|s| parse_time(s, true))
+            .transpose()?,
+    };
+    let operations = runner.history(&filter)?;
+    if output == OutputFormat::Json {
+        let operations = operations
+            .into_iter()
+            .map(|o| HistoryEntryJson {
+                id: o.id,
+                migration_id: o.migration_id,
+                label: o.label,
+                operation: o.operation,
+                direction: o.direction,
+                started_at: o.started_at.map(|t| t.to_rfc3339()),
+                duration_ms: o.duration.map(|d| d.as_millis() as u64),
+                succeeded: o.succeeded,
+                error: o.error,
+                applied_by: o.applied_by,
+                host: o.host,
+                rmmm_version: o.rmmm_version,
+            })
+            .collect();
+        return output::print_json("history", &HistoryJson { operations });
+    }
+    let data = operations
+        .into_iter()
+        .map(|o| HistoryRow {
+            id: o.id,
+            migration: o.migration_id.map(|i| i.to_string()).unwrap_or_default(),
+            label: o.label.unwrap_or_default(),
+            operation: o.operation,
+            direction: o.direction.unwrap_or_default(),
+            started_at: o.started_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            duration: o
+                .duration
+                .map(|d| format!("{:.3}s", d.as_secs_f64()))
+                .unwrap_or_default(),
+            outcome: if o.succeeded { "success" } else { "failure" }.to_string(),
+            error: o.error.unwrap_or_default(),
+            applied_by: o.applied_by.unwrap_or_default(),
+            host: o.host.unwrap_or_default(),
+            rmmm_version: o.rmmm_version.unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    if output == OutputFormat::Plain {
+        output::print_plain(&data, HistoryRow::LENGTH);
+    } else {
+        let table = tabled::Table::new(&data).with(tabled::Style::modern().horizontal_off());
+        println!("{table}");
+    }
+    Ok(())
+}
+
+#[derive(Tabled)]
+struct ParamReportRow {
+    parameter: String,
+    outcome: ParamOutcome,
+    note: String,
+}
+
+fn command_dsn(matches: &clap::ArgMatches, connection: &str) -> anyhow::Result<()> {
+    debug!("Starting command_dsn");
+    let show_password = matches.is_present("show_password");
+    // the parsed DSN is kept for --ping
+    let (converted, report, dsn) = if connection.starts_with("mysql://") {
+        let (dsn, report) = GoDatabaseDsn::from_url(connection)?;
+        let converted = format!("DSN: {}", dsn.to_dsn_string(show_password));
+        (converted, report, None)
+    } else {
+        let dsn = connection.parse::<GoDatabaseDsn>()?;
+        let (url, report) = dsn.to_url(show_password);
+        (format!("URL: {url}"), report, Some(dsn))
+    };
+    println!("{converted}");
+    if !report.is_empty() {
+        let rows = report.into_iter().map(|r| ParamReportRow {
+            parameter: r.name,
+            outcome: r.outcome,
+            note: r.note,
+        });
+        println!(
+            "{}",
+            tabled::Table::new(rows).with(tabled::Style::modern().horizontal_off())
+        );
+    }
+    if matches.is_present("ping") {
+        let opts = match dsn {
+            Some(dsn) => dsn.try_into()?,
+            None => password::opts_from_url(connection)?,
+        };
+        let mut conn = mysql::Conn::new(opts).context("could not connect")?;
+        let version: Option<String> = conn.query_first("SELECT VERSION()")?;
+        println!(
+            "Connected; server version {}",
+            version.unwrap_or_else(|| "unknown".to_string())
+        );
+    }
+    Ok(())
+}
+
 fn command_reset(
     matches: &clap::ArgMatches,
     runner: &MigrationRunner,
     quiet: bool,
 ) -> anyhow::Result<()> {
     debug!("Starting command_reset");
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    reset_tables(matches, runner, quiet)
+}
+
+fn reset_tables(
+    matches: &clap::ArgMatches,
+    runner: &MigrationRunner,
+    quiet: bool,
+) -> anyhow::Result<()> {
     let tables = runner.list_tables()?;
     if !quiet {
         println!("Dropping the following tables:");
         for table in &tables {
             println!(" - {table}");
         }
+        if runner.clear_external_migrations_table(true)? {
+            println!("Clearing {}", runner.migrations_table());
+        }
     }
     if matches.is_present("execute") {
-        for table in tables {
-            runner.drop_table(&table)?;
-        }
+        runner.logged(LogEntry::new(Operation::Reset), || {
+            for table in tables {
+                runner.drop_table(&table)?;
+            }
+            runner.clear_external_migrations_table(false)?;
+            Ok(())
+        })?;
     } else {
         error!("rerun with --execute to execute this reset plan");
     }
@@ -196,10 +988,18 @@ fn command_apply_snapshot(
     runner: &MigrationRunner,
     quiet: bool,
 ) -> anyhow::Result<()> {
-    command_reset(matches, runner, quiet)?;
+    debug!("Starting command_apply_snapshot");
+    let _lock = if matches.is_present("execute") {
+        Some(runner.lock()?)
+    } else {
+        None
+    };
+    reset_tables(matches, runner, quiet)?;
     let schema = state.read_schema()?;
     if matches.is_present("execute") {
-        runner.apply_schema_snapshot(&schema)?;
+        runner.logged(LogEntry::new(Operation::ApplySnapshot), || {
+            runner.apply_schema_snapshot(&schema)
+        })?;
         let run_so_far = runner.list_run_migrations()?;
         println!(
             "Migrations applied after snapshot application: {:?}",
@@ -214,7 +1014,7 @@ fn command_apply_snapshot(
     Ok(())
 }
 
-fn cli() -> clap::Command<'static> {
+pub(crate) fn cli() -> clap::Command<'static> {
     clap::Command::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
@@ -228,10 +1028,29 @@ fn cli() -> clap::Command<'static> {
                 .env("MIGRATION_PATH")
                 .help("Directory in which state is stored"),
         )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .env("RMMM_CONFIG")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Configuration file [default: rmmm.toml in the working directory or the migration path]"),
+        )
+        .arg(
+            Arg::new("env")
+                .short('e')
+                .long("env")
+                .env("RMMM_ENV")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Environment from the configuration file to use [default: default_env from the configuration file]"),
+        )
         .arg(
             Arg::new("quiet")
                 .short('q')
                 .long("quiet")
+                .global(true)
                 .help("Be less noisy when logging"),
         )
         .arg(
@@ -239,8 +1058,20 @@ fn cli() -> clap::Command<'static> {
                 .short('v')
                 .long("verbose")
                 .multiple_occurrences(true)
+                .global(true)
                 .help("Be more noisy when logging"),
         )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .env("RMMM_OUTPUT")
+                .takes_value(true)
+                .global(true)
+                .possible_values(OutputFormat::VALUES)
+                .default_value("table")
+                .help("How to print status, plans and history; json is meant for scripts and carries a schema_version"),
+        )
         .arg(
             Arg::new("database_url")
                 .long("database-url")
@@ -260,13 +1091,93 @@ fn cli() -> clap::Command<'static> {
                 .value_name("DSN")
                 .help("go-style database DSN"),
         )
+        .arg(
+            Arg::new("database_password_file")
+                .long("database-password-file")
+                .env("DATABASE_PASSWORD_FILE")
+                .takes_value(true)
+                .value_name("FILE")
+                .value_hint(clap::ValueHint::FilePath)
+                .help("Read the database password from this file instead of the URL or DSN"),
+        )
+        .arg(
+            Arg::new("defaults_file")
+                .long("defaults-file")
+                .env("RMMM_DEFAULTS_FILE")
+                .takes_value(true)
+                .value_name("FILE")
+                .value_hint(clap::ValueHint::FilePath)
+                .help("Read connection settings from only this MySQL option file [default: /etc/my.cnf, /etc/mysql/my.cnf and ~/.my.cnf]"),
+        )
+        .arg(
+            Arg::new("defaults_group_suffix")
+                .long("defaults-group-suffix")
+                .env("RMMM_DEFAULTS_GROUP_SUFFIX")
+                .takes_value(true)
+                .value_name("SUFFIX")
+                .help("Also read the [client<SUFFIX>] and [rmmm<SUFFIX>] groups of option files"),
+        )
+        .arg(
+            Arg::new("no_defaults")
+                .long("no-defaults")
+                .conflicts_with("defaults_file")
+                .help("Don't read any MySQL option files"),
+        )
+        .arg(
+            Arg::new("lock_timeout")
+                .long("lock-timeout")
+                .env("RMMM_LOCK_TIMEOUT")
+                .takes_value(true)
+                .default_value("60")
+                .value_name("SECONDS")
+                .help("How long to wait for other rmmm processes working on the same database to finish"),
+        )
+        .arg(
+            Arg::new("migrations_table")
+                .long("migrations-table")
+                .env("RMMM_MIGRATIONS_TABLE")
+                .takes_value(true)
+                .default_value("rmmm_migrations")
+                .value_name("[SCHEMA.]TABLE")
+                .help("Table in which to keep track of applied migrations"),
+        )
+        .arg(
+            Arg::new("migration_log_table")
+                .long("migration-log-table")
+                .env("RMMM_MIGRATION_LOG_TABLE")
+                .takes_value(true)
+                .value_name("[SCHEMA.]TABLE")
+                .help("Table in which to log every operation [default: rmmm_migration_log, in the same schema as --migrations-table]"),
+        )
+        .arg(
+            Arg::new("session_var")
+                .long("session-var")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1)
+                .value_name("NAME=VALUE")
+                .help("Session system variable to set on every connection, such as sql_mode or time_zone (may be repeated)"),
+        )
         .group(
             clap::ArgGroup::default()
                 .id("database_config")
-                .args(&["database_url", "database_dsn"])
-                .required(true),
+                .args(&["database_url", "database_dsn"]),
+        )
+        .subcommand(
+            clap::Command::new("status")
+                .about("Show the current status of migrations")
+                .arg(
+                    Arg::new("details")
+                        .short('d')
+                        .long("details")
+                        .help("Also show how long each migration took, and who applied it from where with which version of rmmm"),
+                )
+                .arg(
+                    Arg::new("check")
+                        .long("check")
+                        .help("Exit with 3 if migrations are pending, 4 if applied migrations are missing from disk, 5 if applied migrations were modified, or 6 if a migration is dirty"),
+                ),
         )
-        .subcommand(clap::Command::new("status").about("Show the current status of migrations"))
         .subcommand(
             clap::Command::new("generate")
                 .about("Generate a new migration")
@@ -274,6 +1185,56 @@ fn cli() -> clap::Command<'static> {
                     Arg::new("label")
                         .required(true)
                         .help("Descriptive one-line label for the migration"),
+                )
+                .arg(
+                    Arg::new("sql-file")
+                        .long("sql-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Read the migration from FILE"),
+                )
+                .arg(
+                    Arg::new("from-stdin")
+                        .long("from-stdin")
+                        .conflicts_with("sql-file")
+                        .help("Read the migration from standard input (implies --no-edit)"),
+                )
+                .arg(
+                    Arg::new("downgrade-file")
+                        .long("downgrade-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Read the downgrade from FILE"),
+                )
+                .arg(
+                    Arg::new("with-downgrade")
+                        .long("with-downgrade")
+                        .conflicts_with("downgrade-file")
+                        .help("Draft a downgrade by reversing the upgrade's statements; ones which can't be reversed are marked TODO"),
+                )
+                .arg(
+                    Arg::new("no-edit")
+                        .long("no-edit")
+                        .help("Don't open $EDITOR on the new migration"),
+                )
+                .arg(
+                    Arg::new("template")
+                        .long("template")
+                        .short('t')
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .conflicts_with_all(&["sql-file", "from-stdin"])
+                        .help("Fill in the migration from db/templates/NAME.sql (and NAME.down.sql)"),
+                )
+                .arg(
+                    Arg::new("var")
+                        .long("var")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .number_of_values(1)
+                        .value_name("NAME=VALUE")
+                        .requires("template")
+                        .help("Value for {{NAME}} in the template; {{id}} and {{label}} are always set (may be repeated)"),
                 ),
         )
         .subcommand(
@@ -295,6 +1256,128 @@ fn cli() -> clap::Command<'static> {
                         .long("--no-write-schema")
                         .env("NO_WRITE_SCHEMA")
                         .help("Do not write updated db/structure.sql when done"),
+                )
+                .arg(
+                    Arg::new("allow-modified")
+                        .long("allow-modified")
+                        .help("Upgrade even if applied migrations have been modified since they were run"),
+                )
+                .arg(
+                    Arg::new("allow-out-of-order")
+                        .long("allow-out-of-order")
+                        .help("Apply migrations older than the newest applied one, e.g. after merging a branch"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("retry")
+                .about("Resume a dirty migration from the statement which failed")
+                .arg(
+                    Arg::new("id")
+                        .required(true)
+                        .help("Migration to retry"),
+                )
+                .arg(
+                    Arg::new("from_statement")
+                        .long("from-statement")
+                        .takes_value(true)
+                        .value_name("INDEX")
+                        .help("Index of the statement to resume from, instead of the one which failed"),
+                )
+                .arg(
+                    Arg::new("execute")
+                        .short('x')
+                        .long("execute")
+                        .help("Actually retry (otherwise will just print what would be done)"),
+                )
+                .arg(
+                    Arg::new("no-dump")
+                        .long("--no-write-schema")
+                        .env("NO_WRITE_SCHEMA")
+                        .help("Do not write updated db/structure.sql when done"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("resolve")
+                .about("Clear the dirty flag on a migration which was fixed by hand")
+                .arg(
+                    Arg::new("id")
+                        .required(true)
+                        .help("Migration to resolve"),
+                )
+                .arg(
+                    Arg::new("applied")
+                        .long("applied")
+                        .help("The migration has been fully applied"),
+                )
+                .arg(
+                    Arg::new("rolled-back")
+                        .long("rolled-back")
+                        .help("The migration has been entirely rolled back"),
+                )
+                .group(
+                    clap::ArgGroup::default()
+                        .id("outcome")
+                        .args(&["applied", "rolled-back"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("execute")
+                        .short('x')
+                        .long("execute")
+                        .help("Actually resolve (otherwise will just print what would be done)"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("history")
+                .about("Show the log of everything rmmm has done to the database")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .takes_value(true)
+                        .help("Only show operations on this migration"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("Only show operations started at or after this date (YYYY-MM-DD) or RFC 3339 time"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("Only show operations started before this RFC 3339 time, or on or before this date (YYYY-MM-DD)"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("dsn")
+                .about("Check a connection string and convert between go-style DSNs and mysql:// URLs")
+                .arg(
+                    Arg::new("connection")
+                        .value_name("URL_OR_DSN")
+                        .help("Connection string to convert [default: the configured --database-url or --database-dsn]"),
+                )
+                .arg(
+                    Arg::new("show_password")
+                        .long("show-password")
+                        .help("Include the password in the output instead of redacting it"),
+                )
+                .arg(
+                    Arg::new("ping")
+                        .long("ping")
+                        .help("Also connect to the database and report the server version"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("rehash")
+                .about("Record the current checksums of applied migrations which have been modified on disk")
+                .arg(
+                    Arg::new("execute")
+                        .short('x')
+                        .long("execute")
+                        .help("Actually record the checksums (otherwise will just print what would be done)"),
                 ),
         )
         .subcommand(
@@ -340,27 +1423,70 @@ fn cli() -> clap::Command<'static> {
         )
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let matches = cli().get_matches();
 
     initialize_logging(&matches);
 
-    let current_state = MigrationState::load(matches.value_of("migration_path").unwrap())?;
+    let config = Config::load(&matches)?;
+    let migration_path = lookup(&matches, "migration_path", config.migration_path.clone()).unwrap();
+    let (versioning, layout) = (config.versioning, config.layout);
+    let env = config.into_environment(&matches)?;
+
+    // needs neither the migrations nor a working connection
+    if let Some(("dsn", smatches)) = matches.subcommand() {
+        let connection = smatches
+            .value_of("connection")
+            .or_else(|| matches.value_of("database_url"))
+            .or_else(|| matches.value_of("database_dsn"))
+            .or(env.database_url.as_deref())
+            .or(env.database_dsn.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("no URL or DSN given, and none is configured"))?;
+        command_dsn(smatches, connection)?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let runner = MigrationRunner::from_matches(&matches)?;
+    let current_state = MigrationState::load(migration_path)?;
+
+    // doesn't need a connection either, so that scripts can generate migrations anywhere
+    if let Some(("generate", smatches)) = matches.subcommand() {
+        command_generate(smatches, &current_state, versioning, layout)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let runner = MigrationRunner::from_matches(&matches, &env)?;
 
     match matches.subcommand() {
-        Some(("generate", smatches)) => {
-            current_state.generate(smatches.value_of("label").unwrap())?
+        Some(("downgrade", _)) if !env.allow_downgrade => {
+            anyhow::bail!("downgrades are disabled for this environment in {CONFIG_FILE_NAME}")
         }
-        Some(("status", _)) => {
-            command_status(current_state, runner)?;
+        Some(("reset" | "apply-snapshot", _)) if !env.allow_reset => {
+            anyhow::bail!("resets are disabled for this environment in {CONFIG_FILE_NAME}")
+        }
+        _ => {}
+    }
+
+    match matches.subcommand() {
+        Some(("status", smatches)) => {
+            return command_status(smatches, current_state, runner);
         }
         Some(("upgrade", smatches)) => {
-            command_apply_migrations(smatches, current_state, runner, true)?;
+            command_apply_migrations(smatches, &env, current_state, runner, true)?;
         }
         Some(("downgrade", smatches)) => {
-            command_apply_migrations(smatches, current_state, runner, false)?;
+            command_apply_migrations(smatches, &env, current_state, runner, false)?;
+        }
+        Some(("retry", smatches)) => {
+            command_retry(smatches, &env, current_state, runner)?;
+        }
+        Some(("resolve", smatches)) => {
+            command_resolve(smatches, runner)?;
+        }
+        Some(("history", smatches)) => {
+            command_history(smatches, runner)?;
+        }
+        Some(("rehash", smatches)) => {
+            command_rehash(smatches, current_state, runner)?;
         }
         Some(("apply-snapshot", smatches)) => {
             command_apply_snapshot(
@@ -376,15 +1502,87 @@ fn main() -> anyhow::Result<()> {
             anyhow::bail!("Must pass a command!");
         }
     };
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::cli;
+    use std::collections::BTreeSet;
+
+    use super::{
+        ChecksumStatus, MigrationStatus, MigrationStatusRow, SchemaState, cli, parse_time,
+    };
+    use crate::version::Version;
+
+    fn row(id: u64, status: MigrationStatus, checksum: ChecksumStatus) -> MigrationStatusRow {
+        MigrationStatusRow {
+            id: Version(id),
+            label: String::new(),
+            status,
+            executed_at: String::new(),
+            checksum,
+            duration: String::new(),
+            applied_by: String::new(),
+            host: String::new(),
+            rmmm_version: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_schema_state() {
+        use ChecksumStatus::*;
+        use MigrationStatus::*;
+        let known = BTreeSet::from([Version(1), Version(2)]);
+        let applied = row(1, Executed, Matches);
+        assert_eq!(
+            SchemaState::of(&[applied, row(2, Executed, Matches)], &known),
+            SchemaState::UpToDate
+        );
+        let rows = [
+            row(1, Executed, Matches),
+            row(2, NotExecuted, NotApplicable),
+        ];
+        assert_eq!(SchemaState::of(&rows, &known), SchemaState::Pending);
+        let rows = [
+            row(1, Executed, Modified),
+            row(2, NotExecuted, NotApplicable),
+        ];
+        assert_eq!(SchemaState::of(&rows, &known), SchemaState::ChecksumDrift);
+        let rows = [row(1, Executed, Modified), row(3, Executed, NotApplicable)];
+        assert_eq!(SchemaState::of(&rows, &known), SchemaState::UnknownApplied);
+        let rows = [row(1, OutOfOrder, NotApplicable), row(2, Executed, Matches)];
+        assert_eq!(SchemaState::of(&rows, &known), SchemaState::Pending);
+        let rows = [row(1, Missing, NotApplicable), row(2, Executed, Matches)];
+        assert_eq!(
+            SchemaState::of(&rows, &BTreeSet::from([Version(2)])),
+            SchemaState::UnknownApplied
+        );
+        let rows = [row(1, Dirty, Matches), row(3, Executed, NotApplicable)];
+        assert_eq!(SchemaState::of(&rows, &known), SchemaState::Dirty);
+        assert_eq!(SchemaState::UpToDate.exit_code(), 0);
+    }
 
     #[test]
     fn test_cli() {
         cli().debug_assert();
     }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2026-10-16", false).unwrap().to_rfc3339(),
+            "2026-10-16T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time("2026-10-16", true).unwrap().to_rfc3339(),
+            "2026-10-17T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time("2026-10-16T18:30:00-07:00", true)
+                .unwrap()
+                .to_rfc3339(),
+            "2026-10-17T01:30:00+00:00"
+        );
+        assert!(parse_time("last tuesday", false).is_err());
+    }
 }
@@ -1,30 +1,201 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use anyhow::Context;
 use chrono::{TimeZone, Utc};
+use derive_more::Display;
 use itertools::Itertools;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use mysql::prelude::Queryable;
+use sha2::{Digest, Sha256};
 
+use crate::config::{CONFIG_FILE_NAME, Environment, lookup};
 use crate::go_database_dsn::GoDatabaseDsn;
 use crate::migration_state::MigrationState;
+use crate::option_file::ClientOptions;
+use crate::password;
+use crate::session_variables::{self, REPORTED_VARIABLES, SessionValue};
+use crate::sql_lexer::split_statements;
+use crate::table_name::{TableName, quote_identifier};
+use crate::version::Version;
+
+// MySQL limits user-level lock names to 64 characters
+const MAX_LOCK_NAME_LENGTH: usize = 64;
 
 pub(crate) struct MigrationRunner {
     pool: mysql::Pool,
     tx_opts: mysql::TxOpts,
+    lock_timeout: u32,
+    migrations_table: TableName,
+    log_table: TableName,
+    session_variables: BTreeMap<String, SessionValue>,
+}
+
+/// A MySQL user-level lock (`GET_LOCK`) preventing concurrent runs of rmmm against the same
+/// database. The lock is released when this is dropped.
+pub(crate) struct MigrationLock {
+    conn: mysql::PooledConn,
+    name: String,
+}
+
+impl Drop for MigrationLock {
+    fn drop(&mut self) {
+        debug!("releasing lock {:?}", self.name);
+        if let Err(e) = self.conn.exec_drop("DO RELEASE_LOCK(?)", (&self.name,)) {
+            warn!("could not release lock {:?}: {e}", self.name);
+        }
+    }
+}
+
+// Columns of the tracking table (rmmm_migrations by default). Any that are missing from an existing table (because it was
+// created by an older rmmm) are added the first time it's seen.
+const COLUMNS: &[(&str, &str)] = &[
+    // BIGINT to fit timestamp versions
+    ("id", "BIGINT UNSIGNED NOT NULL PRIMARY KEY"),
+    ("label", "VARCHAR(255) NOT NULL"),
+    ("executed_at", "BIGINT NOT NULL"),
+    ("checksum", "CHAR(64) NULL"),
+    ("dirty", "VARCHAR(16) NULL"),
+    ("failed_statement", "INT NULL"),
+    ("duration_ms", "BIGINT NULL"),
+    ("applied_by", "VARCHAR(255) NULL"),
+    ("host", "VARCHAR(255) NULL"),
+    ("rmmm_version", "VARCHAR(32) NULL"),
+    // so that migrations can be downgraded even once their file is gone
+    ("downgrade_sql", "MEDIUMTEXT NULL"),
+];
+
+// one row of rmmm_migrations, as selected by list_run_migrations: the COLUMNS in order
+type MigrationRow = (
+    u64,
+    String,
+    i64,
+    Option<String>,
+    Option<String>,
+    Option<usize>,
+    Option<u64>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+// values of rmmm_migrations.dirty, which is NULL unless a migration failed part-way through,
+// and of rmmm_migration_log.direction
+const UPGRADE: &str = "upgrade";
+const DOWNGRADE: &str = "downgrade";
+
+// The log table is append-only; rows are never updated or deleted by rmmm, and the table
+// survives `reset` and `apply-snapshot`. Unless configured otherwise, it lives alongside the
+// tracking table.
+const DEFAULT_LOG_TABLE: &str = "rmmm_migration_log";
+
+/// Kinds of operation recorded in rmmm_migration_log
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    #[display(fmt = "migrate")]
+    Migrate,
+    #[display(fmt = "retry")]
+    Retry,
+    /// `resolve --applied`
+    #[display(fmt = "mark-applied")]
+    MarkApplied,
+    /// `resolve --rolled-back`
+    #[display(fmt = "mark-rolled-back")]
+    MarkRolledBack,
+    #[display(fmt = "rehash")]
+    Rehash,
+    #[display(fmt = "apply-snapshot")]
+    ApplySnapshot,
+    #[display(fmt = "reset")]
+    Reset,
+}
+
+/// What is being done, for the purposes of rmmm_migration_log
+#[derive(Debug, Clone, Copy)]
+pub struct LogEntry<'a> {
+    pub operation: Operation,
+    pub migration_id: Option<Version>,
+    pub label: Option<&'a str>,
+    pub is_upgrade: Option<bool>,
+}
+
+impl LogEntry<'_> {
+    pub fn new(operation: Operation) -> Self {
+        LogEntry {
+            operation,
+            migration_id: None,
+            label: None,
+            is_upgrade: None,
+        }
+    }
+}
+
+/// A row of rmmm_migration_log
+#[derive(Debug)]
+pub struct LoggedOperation {
+    pub id: u64,
+    pub migration_id: Option<Version>,
+    pub label: Option<String>,
+    pub operation: String,
+    pub direction: Option<String>,
+    pub started_at: Option<chrono::DateTime<Utc>>,
+    pub duration: Option<std::time::Duration>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    pub applied_by: Option<String>,
+    pub host: Option<String>,
+    pub rmmm_version: Option<String>,
+}
+
+/// Which rows of rmmm_migration_log to show
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub migration_id: Option<Version>,
+    pub since: Option<chrono::DateTime<Utc>>,
+    pub until: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Debug)]
 pub struct ExecutedMigration {
-    pub id: u32,
+    pub id: Version,
+    pub label: String,
     pub executed_at: Option<chrono::DateTime<Utc>>,
+    pub checksum: Option<String>,
+    /// Set if this migration failed part-way through being applied
+    pub dirty: Option<DirtyState>,
+    pub duration: Option<std::time::Duration>,
+    pub applied_by: Option<String>,
+    pub host: Option<String>,
+    pub rmmm_version: Option<String>,
+    /// Recorded when the migration was applied, if it had a downgrade
+    pub downgrade_sql: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct DirtyState {
+    pub is_upgrade: bool,
+    /// Index of the statement that failed, if known
+    pub failed_statement: Option<usize>,
+}
+
+impl std::fmt::Display for DirtyState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = if self.is_upgrade { UPGRADE } else { DOWNGRADE };
+        match self.failed_statement {
+            Some(index) => write!(f, "{direction} failed at statement index {index}"),
+            None => write!(f, "{direction} was interrupted"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct MigrationStep {
-    pub id: u32,
+    pub id: Version,
     pub label: Option<String>,
     pub sql: String,
+    pub checksum: Option<String>,
+    /// For upgrades, the SQL to record for undoing this step later
+    pub downgrade_sql: Option<String>,
 }
 
 #[derive(Debug)]
@@ -35,7 +206,149 @@ pub struct MigrationPlan {
     is_upgrade: bool,
 }
 
+/// IDs of applied migrations which have no file on disk
+pub(crate) fn missing_from_disk(
+    state: &MigrationState,
+    applied: &[ExecutedMigration],
+) -> Vec<Version> {
+    let on_disk = state.all_ids();
+    applied
+        .iter()
+        .map(|m| m.id)
+        .filter(|id| !on_disk.contains(id))
+        .sorted()
+        .collect()
+}
+
+/// IDs of unapplied migrations lower than the highest applied one, which would be applied out
+/// of order
+pub(crate) fn out_of_order(state: &MigrationState, applied: &[ExecutedMigration]) -> Vec<Version> {
+    let applied_ids = applied.iter().map(|m| m.id).collect::<BTreeSet<Version>>();
+    let highest_applied = applied_ids.iter().max().copied().unwrap_or_default();
+    state
+        .all_ids()
+        .difference(&applied_ids)
+        .filter(|&&id| id < highest_applied)
+        .copied()
+        .collect()
+}
+
 impl MigrationPlan {
+    /// Apply everything on disk which hasn't been applied yet, up to `target_revision`
+    pub fn upgrade(
+        state: &MigrationState,
+        applied: &[ExecutedMigration],
+        target_revision: Version,
+        allow_out_of_order: bool,
+    ) -> anyhow::Result<Self> {
+        let highest_id = state.highest_id();
+        if target_revision == Version(0) || target_revision > highest_id {
+            anyhow::bail!("Invalid target revision {}", target_revision);
+        }
+        let missing = missing_from_disk(state, applied);
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "applied migrations {:?} don't exist on disk; check out the code which added them, or downgrade past them first",
+                missing
+            );
+        }
+        let run_ids = applied.iter().map(|m| m.id).collect::<BTreeSet<Version>>();
+        let to_run = state
+            .all_ids()
+            .difference(&run_ids)
+            .filter(|&&i| i <= target_revision)
+            .cloned()
+            .sorted()
+            .collect::<Vec<Version>>();
+        let late = out_of_order(state, applied)
+            .into_iter()
+            .filter(|id| to_run.contains(id))
+            .collect::<Vec<_>>();
+        if !late.is_empty() {
+            let highest_applied = run_ids.iter().max().copied().unwrap_or_default();
+            if allow_out_of_order {
+                warn!(
+                    "applying migrations {late:?} after migration {highest_applied}, out of order"
+                );
+            } else {
+                anyhow::bail!(
+                    "migrations {:?} are older than migration {}, which has already been applied; pass --allow-out-of-order to apply them anyway",
+                    late,
+                    highest_applied
+                );
+            }
+        }
+        let state_by_id = state.migrations_by_id();
+        let steps = to_run
+            .into_iter()
+            .map(|id| {
+                let migration = state_by_id[&id];
+                MigrationStep {
+                    id,
+                    label: migration.label.clone(),
+                    sql: migration.upgrade_text.clone(),
+                    checksum: Some(migration.checksum()),
+                    downgrade_sql: migration.downgrade_text.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(MigrationPlan {
+            steps,
+            is_upgrade: true,
+        })
+    }
+
+    /// Undo every applied migration above `target_revision`, newest first. Migrations whose
+    /// file is gone are undone with the downgrade recorded when they were applied.
+    pub fn downgrade(
+        state: &MigrationState,
+        applied: &[ExecutedMigration],
+        target_revision: Version,
+    ) -> anyhow::Result<Self> {
+        let state_by_id = state.migrations_by_id();
+        let steps = applied
+            .iter()
+            .filter(|m| m.id > target_revision)
+            .sorted_by_key(|m| std::cmp::Reverse(m.id))
+            .map(|executed| {
+                let id = executed.id;
+                let (label, sql) = match state_by_id.get(&id) {
+                    Some(migration) => (
+                        migration.label.clone(),
+                        migration
+                            .downgrade_text
+                            .clone()
+                            .or_else(|| executed.downgrade_sql.clone()),
+                    ),
+                    None => {
+                        if executed.downgrade_sql.is_none() {
+                            anyhow::bail!(
+                                "migration {} doesn't exist on disk, and no downgrade was recorded when it was applied",
+                                id
+                            );
+                        }
+                        info!("migration {id} doesn't exist on disk; using the recorded downgrade");
+                        (Some(executed.label.clone()), executed.downgrade_sql.clone())
+                    }
+                };
+                match sql {
+                    Some(sql) => Ok(MigrationStep {
+                        id,
+                        label,
+                        sql,
+                        checksum: None,
+                        downgrade_sql: None,
+                    }),
+                    None => anyhow::bail!("step {:?} is irreversible", id),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(MigrationPlan {
+            steps,
+            is_upgrade: false,
+        })
+    }
+
     pub fn steps(&self) -> &[MigrationStep] {
         self.steps.as_slice()
     }
@@ -45,183 +358,616 @@ impl MigrationPlan {
     }
 }
 
+/// A value of one of the tracking table's COLUMNS, with the given definition, as a SQL literal
+/// for a snapshot. Strings with backslashes (or which aren't UTF-8) are written in hex, so that
+/// they mean the same whether or not `NO_BACKSLASH_ESCAPES` is set when the snapshot is applied.
+fn sql_literal(value: &mysql::Value, definition: &str) -> String {
+    let is_integer = matches!(definition.split_whitespace().next(), Some("INT" | "BIGINT"));
+    match value {
+        // the text protocol returns numbers as strings, too
+        mysql::Value::Bytes(bytes)
+            if is_integer && !bytes.is_empty() && bytes.iter().all(u8::is_ascii_digit) =>
+        {
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+        mysql::Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(s) if !s.contains('\\') => format!("'{}'", s.replace('\'', "''")),
+            _ => format!("X'{}'", bytes.iter().map(|b| format!("{b:02x}")).join("")),
+        },
+        other => other.as_sql(true),
+    }
+}
+
+/// Take a nullable column out of a row, treating values of the wrong type as NULL
+fn take_column<T: mysql::prelude::FromValue>(row: &mut mysql::Row, column: &str) -> Option<T> {
+    row.take_opt::<Option<T>, _>(column)
+        .and_then(|v| v.ok())
+        .flatten()
+}
+
 impl MigrationRunner {
-    pub fn from_matches(matches: &clap::ArgMatches) -> anyhow::Result<Self> {
-        let opts = if let Some(url) = matches.value_of("database_url") {
-            mysql::Opts::from_url(url)?
-        } else if let Some(dsn) = matches.value_of("database_dsn") {
+    pub fn from_matches(matches: &clap::ArgMatches, env: &Environment) -> anyhow::Result<Self> {
+        // --database-url and --database-dsn are one setting as far as precedence goes
+        let (url, dsn) = match (
+            matches.value_of("database_url"),
+            matches.value_of("database_dsn"),
+        ) {
+            (None, None) => (env.database_url.as_deref(), env.database_dsn.as_deref()),
+            explicit => explicit,
+        };
+        let opts = if let Some(url) = url {
+            debug!("connecting to {}", password::redact_url(url));
+            Some(password::opts_from_url(url)?)
+        } else if let Some(dsn) = dsn {
             let parsed = dsn.parse::<GoDatabaseDsn>()?;
-            parsed.try_into()?
+            debug!("connecting with {parsed:?}");
+            Some(parsed.try_into()?)
         } else {
-            anyhow::bail!("must pass either --database-url or --database-dsn")
+            None
+        };
+        let opts = ClientOptions::load(matches, env)?
+            .merge(opts)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "must pass either --database-url or --database-dsn, set database_url or database_dsn in {}, or configure the [client] group of an option file",
+                    CONFIG_FILE_NAME
+                )
+            })?;
+        let opts = match lookup(
+            matches,
+            "database_password_file",
+            env.database_password_file.clone(),
+        ) {
+            Some(path) => mysql::OptsBuilder::from_opts(opts)
+                .pass(Some(password::read_password_file(path)?))
+                .into(),
+            None => opts,
         };
+        let lock_timeout = lookup(
+            matches,
+            "lock_timeout",
+            env.lock_timeout.map(|t| t.to_string()),
+        )
+        .unwrap()
+        .parse()
+        .context("--lock-timeout must be a whole number of seconds")?;
+        let migrations_table: TableName =
+            lookup(matches, "migrations_table", env.migrations_table.clone())
+                .unwrap()
+                .parse()
+                .context("invalid --migrations-table")?;
+        let log_table = match lookup(
+            matches,
+            "migration_log_table",
+            env.migration_log_table.clone(),
+        ) {
+            Some(t) => t.parse().context("invalid --migration-log-table")?,
+            None => migrations_table.sibling(DEFAULT_LOG_TABLE),
+        };
+        // --session-var overrides the config file one variable at a time
+        let mut session_variables = env
+            .session_variables
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        for assignment in matches.values_of("session_var").into_iter().flatten() {
+            let (name, value) = session_variables::parse_assignment(assignment)?;
+            session_variables.insert(name, value);
+        }
+        // run after anything from the DSN, so that these win
+        let mut init = opts.get_init();
+        init.extend(session_variables::set_statements(&session_variables)?);
+        let opts = mysql::OptsBuilder::from_opts(opts).init(init);
         Ok(MigrationRunner {
             pool: mysql::Pool::new(opts)?,
             tx_opts: mysql::TxOpts::default()
                 .set_isolation_level(Some(mysql::IsolationLevel::RepeatableRead)),
+            lock_timeout,
+            migrations_table,
+            log_table,
+            session_variables,
         })
     }
 
+    /// Take the advisory lock for the current database, waiting up to the configured timeout
+    /// for any other rmmm process holding it to finish
+    pub fn lock(&self) -> anyhow::Result<MigrationLock> {
+        let mut conn = self.pool.get_conn()?;
+        let db_name = conn
+            .query_first::<Option<String>, _>("SELECT DATABASE()")?
+            .flatten()
+            .ok_or_else(|| anyhow::anyhow!("no database selected"))?;
+        let mut name = format!("rmmm:{db_name}");
+        if name.len() > MAX_LOCK_NAME_LENGTH {
+            name = format!("rmmm:{:x}", Sha256::digest(db_name.as_bytes()));
+            name.truncate(MAX_LOCK_NAME_LENGTH);
+        }
+        debug!(
+            "acquiring lock {name:?} (waiting up to {}s)",
+            self.lock_timeout
+        );
+        let acquired = conn
+            .exec_first::<Option<i64>, _, _>("SELECT GET_LOCK(?, ?)", (&name, self.lock_timeout))?
+            .flatten();
+        match acquired {
+            Some(1) => Ok(MigrationLock { conn, name }),
+            Some(_) => {
+                let holder = conn
+                    .exec_first::<Option<u64>, _, _>("SELECT IS_USED_LOCK(?)", (&name,))?
+                    .flatten();
+                match holder {
+                    Some(holder) => anyhow::bail!(
+                        "timed out after {}s waiting for lock {:?}, which is held by MySQL connection {}",
+                        self.lock_timeout,
+                        name,
+                        holder
+                    ),
+                    None => anyhow::bail!(
+                        "timed out after {}s waiting for lock {:?}",
+                        self.lock_timeout,
+                        name
+                    ),
+                }
+            }
+            None => anyhow::bail!("could not acquire lock {:?}", name),
+        }
+    }
+
+    fn table_exists(tx: &mut impl Queryable, table: &TableName) -> anyhow::Result<bool> {
+        let count = tx
+            .exec_first::<u64, _, _>(
+                "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = COALESCE(?, DATABASE()) AND table_name = ?",
+                (&table.schema, &table.name),
+            )?
+            .unwrap_or(0);
+        Ok(count > 0)
+    }
+
+    /// The types of a table's columns, by name
+    fn column_types(
+        tx: &mut impl Queryable,
+        table: &TableName,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        Ok(tx
+            .query_map(format!("SHOW COLUMNS FROM {table}"), |row: mysql::Row| {
+                (
+                    row.get::<String, _>(0).unwrap_or_default(),
+                    row.get::<String, _>(1).unwrap_or_default(),
+                )
+            })?
+            .into_iter()
+            .collect())
+    }
+
+    /// Tables created before timestamp versions were supported have INT version columns, which
+    /// are too small for them
+    fn widen_version_column(
+        tx: &mut impl Queryable,
+        table: &TableName,
+        column: &str,
+        definition: &str,
+    ) -> anyhow::Result<()> {
+        let columns = Self::column_types(tx, table)?;
+        if columns
+            .get(column)
+            .is_some_and(|t| !t.to_ascii_lowercase().starts_with("bigint"))
+        {
+            info!("widening {table}.{column} to fit timestamp versions");
+            tx.query_drop(format!(
+                "ALTER TABLE {table} MODIFY COLUMN {column} {definition}"
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the table lives in the database we're migrating (as opposed to some other schema)
+    fn is_in_current_database(tx: &mut impl Queryable, table: &TableName) -> anyhow::Result<bool> {
+        Ok(tx
+            .exec_first::<Option<bool>, _, _>(
+                "SELECT COALESCE(?, DATABASE()) = DATABASE()",
+                (&table.schema,),
+            )?
+            .flatten()
+            .unwrap_or(false))
+    }
+
+    /// Make sure that the tracking table exists (if `create` is set) and has all of the columns
+    /// this version of rmmm expects. Returns whether the table exists.
+    ///
+    /// This changes the schema, so it's only done by commands holding the lock; reads cope with
+    /// older tables instead.
+    fn prepare_migrations_table(
+        &self,
+        tx: &mut impl Queryable,
+        create: bool,
+    ) -> anyhow::Result<bool> {
+        let table = &self.migrations_table;
+        if !Self::table_exists(tx, table)? {
+            if !create {
+                return Ok(false);
+            }
+            debug!("creating {table} table");
+            tx.query_drop(format!(
+                "CREATE TABLE {table}({})",
+                COLUMNS
+                    .iter()
+                    .map(|(column, definition)| format!("{column} {definition}"))
+                    .join(", ")
+            ))?;
+            return Ok(true);
+        }
+        let columns = Self::column_types(tx, table)?;
+        for (column, definition) in COLUMNS {
+            if !columns.contains_key(*column) {
+                info!("adding column {column} to {table}");
+                tx.query_drop(format!(
+                    "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+                ))?;
+            }
+        }
+        Self::widen_version_column(tx, table, "id", "BIGINT UNSIGNED NOT NULL")?;
+        Ok(true)
+    }
+
+    /// The values of the configured session variables, plus a few which commonly matter to
+    /// migrations, as seen by a new connection
+    pub fn effective_session_variables(&self) -> anyhow::Result<Vec<(String, Option<String>)>> {
+        let mut conn = self.pool.get_conn()?;
+        REPORTED_VARIABLES
+            .iter()
+            .map(|name| name.to_string())
+            .chain(self.session_variables.keys().cloned())
+            .unique()
+            .map(|name| {
+                let value: Option<Option<String>> =
+                    conn.query_first(format!("SELECT CAST(@@SESSION.{name} AS CHAR)"))?;
+                Ok((name, value.flatten()))
+            })
+            .collect()
+    }
+
     pub fn list_run_migrations(&self) -> anyhow::Result<Vec<ExecutedMigration>> {
         let mut tx = self.pool.start_transaction(self.tx_opts)?;
-        if tx
-            .query_iter("SHOW TABLE STATUS LIKE 'rmmm_migrations'")?
-            .count()
-            == 0
-        {
-            warn!(
-                "rmmm_migrations table does not exist; assuming no migrations have been run at all"
-            );
+        let table = &self.migrations_table;
+        if !Self::table_exists(&mut tx, table)? {
+            warn!("{table} table does not exist; assuming no migrations have been run at all");
             return Ok(vec![]);
         }
+        // columns added by later versions of rmmm read as NULL until something is applied
+        let existing = Self::column_types(&mut tx, table)?;
+        let columns = COLUMNS
+            .iter()
+            .map(|(column, _)| {
+                if existing.contains_key(*column) {
+                    column.to_string()
+                } else {
+                    format!("NULL AS {column}")
+                }
+            })
+            .join(", ");
         let rows = tx.query_map(
-            "SELECT id, executed_at FROM rmmm_migrations",
-            |(id, executed_at)| ExecutedMigration {
+            format!("SELECT {columns} FROM {table}"),
+            |(
                 id,
+                label,
+                executed_at,
+                checksum,
+                dirty,
+                failed_statement,
+                duration_ms,
+                applied_by,
+                host,
+                rmmm_version,
+                downgrade_sql,
+            ): MigrationRow| ExecutedMigration {
+                id: Version(id),
+                label,
                 executed_at: Utc.timestamp_opt(executed_at, 0).single(),
+                checksum,
+                dirty: dirty.map(|direction| DirtyState {
+                    is_upgrade: direction != DOWNGRADE,
+                    failed_statement,
+                }),
+                duration: duration_ms.map(std::time::Duration::from_millis),
+                applied_by,
+                host,
+                rmmm_version,
+                downgrade_sql,
             },
         )?;
+        tx.commit()?;
         Ok(rows)
     }
 
-    pub fn plan(
-        &self,
-        state: &MigrationState,
-        target_revision: u32,
-        is_upgrade: bool,
-    ) -> anyhow::Result<MigrationPlan> {
-        if is_upgrade {
-            self.plan_upgrade(state, target_revision)
+    /// Clear the dirty flag on a migration, either leaving it recorded as applied or forgetting
+    /// that it was ever applied
+    pub fn resolve(&self, id: Version, applied: bool) -> anyhow::Result<()> {
+        let mut tx = self.pool.start_transaction(self.tx_opts)?;
+        if applied {
+            tx.exec_drop(
+                format!(
+                    "UPDATE {} SET dirty = NULL, failed_statement = NULL WHERE id = ?",
+                    self.migrations_table
+                ),
+                (id,),
+            )?;
         } else {
-            self.plan_downgrade(state, target_revision)
+            tx.exec_drop(
+                format!("DELETE FROM {} WHERE id = ?", self.migrations_table),
+                (id,),
+            )?;
         }
+        tx.commit()?;
+        Ok(())
     }
 
-    pub fn plan_upgrade(
+    /// Replace the recorded checksum of an applied migration
+    pub fn update_checksum(&self, id: Version, checksum: &str) -> anyhow::Result<()> {
+        // the checksum column may not have been added yet; DDL would commit the transaction, so
+        // it's done first
+        self.prepare_migrations_table(&mut self.pool.get_conn()?, false)?;
+        let mut tx = self.pool.start_transaction(self.tx_opts)?;
+        tx.exec_drop(
+            format!(
+                "UPDATE {} SET checksum = ? WHERE id = ?",
+                self.migrations_table
+            ),
+            (checksum, id),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn now(&self) -> u64 {
+        std::time::UNIX_EPOCH.elapsed().unwrap().as_secs()
+    }
+
+    /// Run `f`, recording what it was and whether it worked in rmmm_migration_log.
+    ///
+    /// Failing to write the log is not fatal, and never hides an error from `f`.
+    pub fn logged<T>(
         &self,
-        state: &MigrationState,
-        target_revision: u32,
-    ) -> anyhow::Result<MigrationPlan> {
-        let highest_id = state.highest_id();
-        if target_revision == 0 || target_revision > highest_id {
-            anyhow::bail!("Invalid target revision {}", target_revision);
+        entry: LogEntry,
+        f: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let started_at = self.now();
+        let start = std::time::Instant::now();
+        let result = f();
+        let error = result.as_ref().err().map(|e| format!("{e:#}"));
+        if let Err(e) = self.write_log(&entry, started_at, start.elapsed(), error) {
+            warn!("could not write to {}: {e:#}", self.log_table);
         }
-        let run_ids = self
-            .list_run_migrations()?
-            .into_iter()
-            .map(|m| m.id)
-            .collect::<BTreeSet<u32>>();
-
-        let state_by_id = state.migrations_by_id();
-        let to_run = state
-            .all_ids()
-            .difference(&run_ids)
-            .filter(|&&i| i <= target_revision)
-            .cloned()
-            .sorted()
-            .collect::<Vec<u32>>();
-        let steps = to_run
-            .into_iter()
-            .map(|id| {
-                let step = state_by_id.get(&id).unwrap();
-                MigrationStep {
-                    id,
-                    label: step.label.clone(),
-                    sql: step.upgrade_text.clone(),
-                }
-            })
-            .collect::<Vec<_>>();
-        Ok(MigrationPlan {
-            steps,
-            is_upgrade: true,
-        })
+        result
     }
 
-    pub fn plan_downgrade(
+    fn write_log(
         &self,
-        state: &MigrationState,
-        target_revision: u32,
-    ) -> anyhow::Result<MigrationPlan> {
-        let state_by_id = state.migrations_by_id();
-        let run_ids = self
-            .list_run_migrations()?
-            .into_iter()
-            .map(|m| m.id)
-            .collect::<BTreeSet<u32>>();
+        entry: &LogEntry,
+        started_at: u64,
+        duration: std::time::Duration,
+        error: Option<String>,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.pool.get_conn()?;
+        conn.query_drop(format!(
+            "CREATE TABLE IF NOT EXISTS {}(\
+            id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY, \
+            migration_id BIGINT UNSIGNED NULL, \
+            label VARCHAR(255) NULL, \
+            operation VARCHAR(32) NOT NULL, \
+            direction VARCHAR(16) NULL, \
+            started_at BIGINT NOT NULL, \
+            duration_ms BIGINT NOT NULL, \
+            outcome VARCHAR(16) NOT NULL, \
+            error TEXT NULL, \
+            applied_by VARCHAR(255) NULL, \
+            host VARCHAR(255) NULL, \
+            rmmm_version VARCHAR(32) NULL, \
+            KEY idx_migration_id (migration_id), \
+            KEY idx_started_at (started_at))",
+            self.log_table
+        ))?;
+        Self::widen_version_column(
+            &mut conn,
+            &self.log_table,
+            "migration_id",
+            "BIGINT UNSIGNED NULL",
+        )?;
+        let applied_by = self.applied_by(&mut conn)?;
+        let host = gethostname::gethostname().to_string_lossy().into_owned();
+        let direction = entry
+            .is_upgrade
+            .map(|u| if u { UPGRADE } else { DOWNGRADE });
+        let outcome = if error.is_none() {
+            "success"
+        } else {
+            "failure"
+        };
+        conn.exec_drop(
+            format!(
+                "INSERT INTO {}(migration_id, label, operation, direction, started_at, duration_ms, outcome, error, applied_by, host, rmmm_version) \
+                VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                self.log_table
+            ),
+            (
+                entry.migration_id,
+                entry.label,
+                entry.operation.to_string(),
+                direction,
+                started_at,
+                duration.as_millis() as u64,
+                outcome,
+                error,
+                applied_by,
+                host,
+                clap::crate_version!(),
+            ),
+        )?;
+        Ok(())
+    }
 
-        let to_run = run_ids
+    /// Read rmmm_migration_log, oldest first
+    pub fn history(&self, filter: &HistoryFilter) -> anyhow::Result<Vec<LoggedOperation>> {
+        let mut tx = self.pool.start_transaction(self.tx_opts)?;
+        if !Self::table_exists(&mut tx, &self.log_table)? {
+            return Ok(vec![]);
+        }
+        let mut conditions = vec!["1 = 1"];
+        let mut params: Vec<mysql::Value> = vec![];
+        if let Some(id) = filter.migration_id {
+            conditions.push("migration_id = ?");
+            params.push(id.into());
+        }
+        if let Some(since) = filter.since {
+            conditions.push("started_at >= ?");
+            params.push(since.timestamp().into());
+        }
+        if let Some(until) = filter.until {
+            conditions.push("started_at < ?");
+            params.push(until.timestamp().into());
+        }
+        let query = format!(
+            "SELECT id, migration_id, label, operation, direction, started_at, duration_ms, outcome, error, applied_by, host, rmmm_version \
+            FROM {} WHERE {} ORDER BY id ASC",
+            self.log_table,
+            conditions.join(" AND ")
+        );
+        let rows = tx.exec_map(query, params, |mut row: mysql::Row| LoggedOperation {
+            id: take_column(&mut row, "id").unwrap_or_default(),
+            migration_id: take_column(&mut row, "migration_id").map(Version),
+            label: take_column(&mut row, "label"),
+            operation: take_column(&mut row, "operation").unwrap_or_default(),
+            direction: take_column(&mut row, "direction"),
+            started_at: take_column(&mut row, "started_at")
+                .and_then(|t| Utc.timestamp_opt(t, 0).single()),
+            duration: take_column(&mut row, "duration_ms").map(std::time::Duration::from_millis),
+            succeeded: take_column::<String>(&mut row, "outcome").as_deref() == Some("success"),
+            error: take_column(&mut row, "error"),
+            applied_by: take_column(&mut row, "applied_by"),
+            host: take_column(&mut row, "host"),
+            rmmm_version: take_column(&mut row, "rmmm_version"),
+        })?;
+        Ok(rows)
+    }
+
+    /// The local user running rmmm, falling back to the MySQL user if that can't be determined
+    fn applied_by(&self, conn: &mut impl Queryable) -> anyhow::Result<Option<String>> {
+        if let Some(user) = ["USER", "LOGNAME", "USERNAME"]
             .iter()
-            .filter(|&&i| i > target_revision)
-            .cloned()
-            .collect::<Vec<u32>>();
+            .find_map(|v| std::env::var(v).ok().filter(|u| !u.is_empty()))
+        {
+            return Ok(Some(user));
+        }
+        Ok(conn.query_first("SELECT CURRENT_USER()")?)
+    }
 
-        let steps = to_run
-            .into_iter()
-            .rev()
-            .map(|id| {
-                let step = state_by_id.get(&id).unwrap();
-                if let Some(sql) = step.downgrade_text.as_ref() {
-                    Ok(MigrationStep {
-                        id,
-                        label: step.label.clone(),
-                        sql: sql.clone(),
-                    })
-                } else {
-                    anyhow::bail!("step {:?} is irreversible", id);
-                }
-            })
-            .collect::<anyhow::Result<Vec<_>>>()?;
-        Ok(MigrationPlan {
-            steps,
-            is_upgrade: false,
-        })
+    /// Apply each step of the plan in turn, recording each one as soon as it is done.
+    ///
+    /// MySQL commits DDL implicitly, so there is no point wrapping migrations in a transaction;
+    /// instead, a migration is marked dirty before it starts, and if one of its statements fails,
+    /// the index of that statement is recorded so that it can be retried from there.
+    pub fn execute(&self, plan: &MigrationPlan) -> anyhow::Result<()> {
+        for step in &plan.steps {
+            self.execute_step(step, plan.is_upgrade, 0, Operation::Migrate)?;
+        }
+        Ok(())
     }
 
-    fn now(&self) -> u64 {
-        std::time::UNIX_EPOCH.elapsed().unwrap().as_secs()
+    /// Apply a single step, skipping the statements before `first_statement`
+    pub fn execute_step(
+        &self,
+        step: &MigrationStep,
+        is_upgrade: bool,
+        first_statement: usize,
+        operation: Operation,
+    ) -> anyhow::Result<()> {
+        let entry = LogEntry {
+            operation,
+            migration_id: Some(step.id),
+            label: step.label.as_deref(),
+            is_upgrade: Some(is_upgrade),
+        };
+        self.logged(entry, || {
+            self.execute_step_unlogged(step, is_upgrade, first_statement)
+        })
     }
 
-    pub fn execute(&self, plan: MigrationPlan) -> anyhow::Result<()> {
-        let mut tx = self.pool.start_transaction(self.tx_opts)?;
-        if tx
-            .query_iter("SHOW TABLE STATUS LIKE 'rmmm_migrations'")?
-            .count()
-            == 0
-        {
-            debug!("creating rmmm_migrations table");
-            tx.query_drop("CREATE TABLE rmmm_migrations(id INT NOT NULL PRIMARY KEY, label VARCHAR(255) NOT NULL, executed_at BIGINT NOT NULL)")?;
-        }
-        let insert_stmt =
-            tx.prep("INSERT INTO rmmm_migrations(id, label, executed_at) VALUES(?, ?, ?)")?;
-        let delete_stmt = tx.prep("DELETE FROM rmmm_migrations WHERE id = ?")?;
-        for step in plan.steps {
-            for command in step.sql.split(";\n") {
-                let command = command.replace('\n', " ").trim().to_owned();
-                if command.is_empty() {
-                    continue;
+    fn execute_step_unlogged(
+        &self,
+        step: &MigrationStep,
+        is_upgrade: bool,
+        first_statement: usize,
+    ) -> anyhow::Result<()> {
+        let statements = split_statements(&step.sql)
+            .with_context(|| format!("Could not parse migration {}", step.id))?;
+        let mut conn = self.pool.get_conn()?;
+        self.prepare_migrations_table(&mut conn, true)?;
+        let table = &self.migrations_table;
+        if is_upgrade {
+            let applied_by = self.applied_by(&mut conn)?;
+            let host = gethostname::gethostname().to_string_lossy().into_owned();
+            conn.exec_drop(
+                format!("INSERT INTO {table}(id, label, executed_at, checksum, dirty, failed_statement, applied_by, host, rmmm_version, downgrade_sql) VALUES(?, ?, ?, ?, ?, NULL, ?, ?, ?, ?) \
+                ON DUPLICATE KEY UPDATE label = VALUES(label), checksum = VALUES(checksum), dirty = VALUES(dirty), failed_statement = NULL, \
+                applied_by = VALUES(applied_by), host = VALUES(host), rmmm_version = VALUES(rmmm_version), downgrade_sql = VALUES(downgrade_sql)"),
+                (
+                    step.id,
+                    &step.label,
+                    self.now(),
+                    &step.checksum,
+                    UPGRADE,
+                    applied_by,
+                    host,
+                    clap::crate_version!(),
+                    &step.downgrade_sql,
+                ),
+            )?;
+        } else {
+            conn.exec_drop(
+                format!("UPDATE {table} SET dirty = ?, failed_statement = NULL WHERE id = ?"),
+                (DOWNGRADE, step.id),
+            )?;
+        }
+        let start = std::time::Instant::now();
+        for (index, statement) in statements.iter().enumerate().skip(first_statement) {
+            debug!("executing {:?}", statement.text);
+            if let Err(e) = conn.query_drop(statement.text) {
+                // the statement's error is what matters, even if the connection is gone and
+                // this can't be recorded
+                if let Err(update_error) = conn.exec_drop(
+                    format!("UPDATE {table} SET failed_statement = ? WHERE id = ?"),
+                    (index, step.id),
+                ) {
+                    warn!(
+                        "could not record that statement {index} of migration {} failed: {update_error:#}",
+                        step.id
+                    );
                 }
-                debug!("executing {command:?}");
-                tx.query_drop(command)?;
-            }
-            if plan.is_upgrade {
-                tx.exec_drop(&insert_stmt, (step.id, step.label, self.now()))?;
-            } else {
-                tx.exec_drop(&delete_stmt, (step.id,))?;
+                return Err(e).with_context(|| {
+                    format!(
+                        "Error in migration {} at statement index {} (line {})",
+                        step.id, index, statement.line
+                    )
+                });
             }
         }
-        tx.commit()?;
+        if is_upgrade {
+            conn.exec_drop(
+                format!("UPDATE {table} SET executed_at = ?, duration_ms = ?, dirty = NULL, failed_statement = NULL WHERE id = ?"),
+                (self.now(), start.elapsed().as_millis() as u64, step.id),
+            )?;
+        } else {
+            conn.exec_drop(format!("DELETE FROM {table} WHERE id = ?"), (step.id,))?;
+        }
         Ok(())
     }
 
     pub fn apply_schema_snapshot(&self, schema: &str) -> anyhow::Result<()> {
         let mut tx = self.pool.start_transaction(self.tx_opts)?;
-        for command in schema.split(";\n") {
-            let command = command.replace('\n', " ").trim().to_owned();
-            if command.is_empty() {
-                continue;
-            }
-            debug!("executing {command:?}");
-            tx.query_drop(command)?
+        let statements = split_statements(schema).context("Could not parse schema snapshot")?;
+        for statement in statements {
+            debug!("executing {:?}", statement.text);
+            tx.query_drop(statement.text)
+                .with_context(|| format!("Error in schema snapshot at line {}", statement.line))?;
         }
         tx.commit()?;
         Ok(())
@@ -234,10 +980,36 @@ impl MigrationRunner {
             .into_iter()
             .next()
             .unwrap();
-        let stmt =
-            tx.prep("SELECT TABLE_NAME FROM information_schema.tables WHERE table_schema=?")?;
-        tx.exec_map(stmt, (db_name,), |table_name: String| table_name)
-            .context("Could not list tables")
+        // the log table is never dropped or dumped
+        let stmt = tx.prep(
+            "SELECT TABLE_NAME FROM information_schema.tables WHERE table_schema=? \
+            AND NOT (table_schema = COALESCE(?, DATABASE()) AND TABLE_NAME = ?)",
+        )?;
+        tx.exec_map(
+            stmt,
+            (db_name, &self.log_table.schema, &self.log_table.name),
+            |table_name: String| table_name,
+        )
+        .context("Could not list tables")
+    }
+
+    /// If the tracking table lives outside of the database being migrated, dropping every table
+    /// doesn't clear it, so do that separately. Returns whether there was anything to clear.
+    pub fn clear_external_migrations_table(&self, dry_run: bool) -> anyhow::Result<bool> {
+        let mut tx = self.pool.start_transaction(self.tx_opts)?;
+        let table = &self.migrations_table;
+        if !Self::table_exists(&mut tx, table)? || Self::is_in_current_database(&mut tx, table)? {
+            return Ok(false);
+        }
+        if !dry_run {
+            tx.query_drop(format!("DELETE FROM {table}"))?;
+            tx.commit()?;
+        }
+        Ok(true)
+    }
+
+    pub fn migrations_table(&self) -> &TableName {
+        &self.migrations_table
     }
 
     pub fn drop_table(&self, table_name: &str) -> anyhow::Result<()> {
@@ -264,14 +1036,45 @@ impl MigrationRunner {
             lines.extend(schema);
             lines.extend(vec!["".to_string()]);
         }
-        if tables.contains(&"rmmm_migrations".to_owned()) {
+        let table = &self.migrations_table;
+        if Self::table_exists(&mut tx, table)? {
             lines.extend(vec!["".to_string()]);
+            if !Self::is_in_current_database(&mut tx, table)? {
+                // the tracking table lives elsewhere, so it won't have been dumped above, and
+                // won't be dropped when this snapshot is applied
+                let schema = tx
+                    .query_map(
+                        format!("SHOW CREATE TABLE {table}"),
+                        |(_table_name, schema): (String, String)| schema,
+                    )?
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                let unqualified = format!("CREATE TABLE {}", quote_identifier(&table.name));
+                let schema = schema.strip_prefix(&unqualified).ok_or_else(|| {
+                    anyhow::anyhow!("unexpected SHOW CREATE TABLE output for {}", table)
+                })?;
+                lines.push(format!("CREATE TABLE IF NOT EXISTS {table}{schema};"));
+                lines.push(format!("DELETE FROM {table};"));
+            }
+            // every column is kept, so that the downgrade SQL and the record of who applied what
+            // survive the snapshot
+            let existing = Self::column_types(&mut tx, table)?;
+            let columns = COLUMNS
+                .iter()
+                .filter(|(column, _)| existing.contains_key(*column))
+                .collect::<Vec<_>>();
+            let names = columns.iter().map(|(column, _)| column).join(", ");
             lines.extend(tx.query_map(
-                "SELECT id, label FROM rmmm_migrations ORDER BY id ASC",
-                |(id, label): (u64, String)| {
-                    format!(
-                        "INSERT INTO rmmm_migrations(id, label, executed_at) VALUES({id}, '{label}', UNIX_TIMESTAMP());",
-                    )
+                format!("SELECT {names} FROM {table} ORDER BY id ASC"),
+                |row: mysql::Row| {
+                    let values = row
+                        .unwrap()
+                        .iter()
+                        .zip(&columns)
+                        .map(|(value, (_, definition))| sql_literal(value, definition))
+                        .join(", ");
+                    format!("INSERT INTO {table}({names}) VALUES({values});")
                 },
             )?);
         }
@@ -279,3 +1082,123 @@ impl MigrationRunner {
         Ok(lines.join("\n"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ExecutedMigration, MigrationPlan, sql_literal};
+    use crate::migration_state::MigrationState;
+    use crate::version::Version;
+
+    fn state(ids: &[u32]) -> (tempfile::TempDir, MigrationState) {
+        let wd = tempfile::TempDir::new().unwrap();
+        let dir = wd.path().join("migrations");
+        std::fs::create_dir_all(&dir).unwrap();
+        for id in ids {
+            std::fs::write(
+                dir.join(format!("v{id}.sql")),
+                format!("CREATE TABLE t{id}(id INT)"),
+            )
+            .unwrap();
+            std::fs::write(
+                dir.join(format!("v{id}_downgrade.sql")),
+                format!("DROP TABLE t{id}"),
+            )
+            .unwrap();
+        }
+        let state = MigrationState::load(wd.path()).unwrap();
+        (wd, state)
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        let bytes = |s: &str| mysql::Value::Bytes(s.as_bytes().to_vec());
+        assert_eq!(sql_literal(&bytes("42"), "BIGINT NULL"), "42");
+        assert_eq!(
+            sql_literal(&bytes("Add user's email"), "VARCHAR(255) NOT NULL"),
+            "'Add user''s email'"
+        );
+        assert_eq!(sql_literal(&bytes(r"a\b"), "MEDIUMTEXT NULL"), "X'615c62'");
+        assert_eq!(sql_literal(&mysql::Value::NULL, "MEDIUMTEXT NULL"), "NULL");
+        assert_eq!(
+            sql_literal(&mysql::Value::Bytes(vec![0xff, b'a']), "VARCHAR(255) NULL"),
+            "X'ff61'"
+        );
+        // only the type decides, not whatever else is in the definition
+        assert_eq!(sql_literal(&bytes("1"), "POINT NULL"), "'1'");
+        assert_eq!(sql_literal(&bytes("1"), "VARCHAR(16) NULL"), "'1'");
+    }
+
+    fn ids(plan: &MigrationPlan) -> Vec<Version> {
+        plan.steps().iter().map(|s| s.id).collect()
+    }
+
+    fn applied(id: u64, downgrade_sql: Option<&str>) -> ExecutedMigration {
+        ExecutedMigration {
+            id: Version(id),
+            label: format!("migration {id}"),
+            executed_at: None,
+            checksum: None,
+            dirty: None,
+            duration: None,
+            applied_by: None,
+            host: None,
+            rmmm_version: None,
+            downgrade_sql: downgrade_sql.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let (_wd, state) = state(&[1, 2, 3]);
+        let plan = MigrationPlan::upgrade(&state, &[applied(1, None)], Version(3), false).unwrap();
+        assert_eq!(ids(&plan), vec![Version(2), Version(3)]);
+        assert_eq!(
+            plan.steps()[0].downgrade_sql.as_deref(),
+            Some("DROP TABLE t2")
+        );
+        let plan = MigrationPlan::upgrade(&state, &[applied(1, None)], Version(2), false).unwrap();
+        assert_eq!(ids(&plan), vec![Version(2)]);
+        assert!(MigrationPlan::upgrade(&state, &[], Version(4), false).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_validation() {
+        let (_wd, state) = state(&[1, 2, 3]);
+        let err = MigrationPlan::upgrade(
+            &state,
+            &[applied(1, None), applied(4, None)],
+            Version(3),
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("[4] don't exist on disk"), "{err}");
+
+        let out_of_order = [applied(1, None), applied(3, None)];
+        let err = MigrationPlan::upgrade(&state, &out_of_order, Version(3), false).unwrap_err();
+        assert!(err.to_string().contains("--allow-out-of-order"), "{err}");
+        let plan = MigrationPlan::upgrade(&state, &out_of_order, Version(3), true).unwrap();
+        assert_eq!(ids(&plan), vec![Version(2)]);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let (_wd, state) = state(&[1, 2]);
+        let executed = [
+            applied(1, None),
+            applied(2, None),
+            applied(3, Some("DROP TABLE t3")),
+        ];
+        let plan = MigrationPlan::downgrade(&state, &executed, Version(1)).unwrap();
+        assert_eq!(ids(&plan), vec![Version(3), Version(2)]);
+        assert_eq!(plan.steps()[0].sql, "DROP TABLE t3");
+        assert_eq!(plan.steps()[0].label.as_deref(), Some("migration 3"));
+        assert_eq!(plan.steps()[1].sql, "DROP TABLE t2");
+
+        let executed = [applied(1, None), applied(3, None)];
+        let err = MigrationPlan::downgrade(&state, &executed, Version(0)).unwrap_err();
+        assert!(
+            err.to_string().contains("migration 3 doesn't exist"),
+            "{err}"
+        );
+    }
+}
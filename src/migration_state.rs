@@ -1,40 +1,195 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::fmt;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use itertools::Itertools;
-use log::debug;
+use log::{debug, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::downgrade::{TODO_MARKER, suggest_downgrade};
+use crate::sql_lexer::strip_comments;
+use crate::template::Template;
+use crate::version::{Version, Versioning};
 
 const DEFAULT_EDITOR: &str = "vim";
 
+// What new migrations say when they weren't given any SQL
+const UPGRADE_PLACEHOLDER: &str =
+    "-- Delete this comment and put your migration here. Blank lines and comments are ignored.";
+const DOWNGRADE_PLACEHOLDER: &str =
+    "-- Put SQL undoing the migration here, or leave this section empty if it can't be undone.";
+
+// Marker lines starting the up and down sections of a single-file migration
+const UP_MARKER: &str = "-- +up";
+const DOWN_MARKER: &str = "-- +down";
+
+// Heads downgrades drafted by `generate --with-downgrade`
+const DRAFT_HEADER: &str =
+    "-- Drafted by rmmm from the upgrade; check it over before relying on it.";
+
+/// How `generate` lays out new migrations, set with `layout` in rmmm.toml. Both layouts are
+/// always loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Layout {
+    /// `v42__label.sql` and `v42__label.down.sql`
+    #[default]
+    Separate,
+    /// Just `v42__label.sql`, with `-- +up` and `-- +down` sections
+    SingleFile,
+}
+
+/// What `generate` should write
+#[derive(Debug, Default)]
+pub(crate) struct NewMigration {
+    pub label: String,
+    pub versioning: Versioning,
+    pub layout: Layout,
+    /// If unset, the migration comes from the template, or else just has instructions in it
+    pub upgrade_sql: Option<String>,
+    pub downgrade_sql: Option<String>,
+    pub template: Option<Template>,
+    /// Values for `{{name}}` variables in the template, besides `id` and `label`
+    pub variables: BTreeMap<String, String>,
+    /// Whether to open `$EDITOR` on the migration before saving it
+    pub edit: bool,
+    /// Whether to draft a downgrade from the upgrade SQL if no downgrade was given
+    pub suggest_downgrade: bool,
+}
+
+/// Run `editor` on `path`. Like git, the editor may have arguments, as in `code --wait`.
+fn run_editor(editor: &str, path: &Path) -> anyhow::Result<()> {
+    let words = shell_words::split(editor)
+        .with_context(|| format!("Could not parse editor command {editor:?}"))?;
+    let (program, args) = words
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("$EDITOR is empty"))?;
+    let status = std::process::Command::new(program)
+        .args(args)
+        .arg(path)
+        .status()
+        .with_context(|| {
+            format!("Could not start editor {editor:?}; try setting $EDITOR to something useful")
+        })?;
+    if !status.success() {
+        anyhow::bail!("Editor exited non-0, discarding migration");
+    }
+    Ok(())
+}
+
+/// Split a single-file migration into its up and down sections. Returns `None` if there are no
+/// section markers.
+///
+/// Lines outside of each section are blanked rather than removed, so that line numbers in
+/// errors still match the file. Anything before `-- +up` (such as the header) counts as part of
+/// the upgrade.
+pub(crate) fn split_sections(text: &str) -> anyhow::Result<Option<(String, String)>> {
+    let is_marker = |line: &str, marker: &str| line.trim().eq_ignore_ascii_case(marker);
+    if !text
+        .lines()
+        .any(|l| is_marker(l, UP_MARKER) || is_marker(l, DOWN_MARKER))
+    {
+        return Ok(None);
+    }
+    let (mut up, mut down) = (String::new(), String::new());
+    let (mut seen_up, mut in_down) = (false, false);
+    for (number, line) in text.split_inclusive('\n').enumerate() {
+        let blank = if line.ends_with('\n') { "\n" } else { "" };
+        if is_marker(line, UP_MARKER) {
+            if seen_up || in_down {
+                anyhow::bail!("unexpected {} on line {}", UP_MARKER, number + 1);
+            }
+            seen_up = true;
+            up.push_str(blank);
+            down.push_str(blank);
+        } else if is_marker(line, DOWN_MARKER) {
+            if in_down {
+                anyhow::bail!("unexpected {} on line {}", DOWN_MARKER, number + 1);
+            }
+            in_down = true;
+            up.push_str(blank);
+            down.push_str(blank);
+        } else if in_down {
+            up.push_str(blank);
+            down.push_str(line);
+        } else {
+            up.push_str(line);
+            down.push_str(blank);
+        }
+    }
+    Ok(Some((up, down)))
+}
+
+/// A downgrade undoing `upgrade` as far as that can be worked out, or `None` if the upgrade has
+/// no statements
+fn draft_downgrade(upgrade: &str) -> anyhow::Result<Option<String>> {
+    let suggestion = suggest_downgrade(upgrade)?;
+    if suggestion.sql.is_empty() {
+        return Ok(None);
+    }
+    if suggestion.todo > 0 {
+        warn!(
+            "{} statement(s) in the drafted downgrade must be reversed by hand; look for {}",
+            suggestion.todo, TODO_MARKER
+        );
+    }
+    Ok(Some(format!("{DRAFT_HEADER}\n{}\n", suggestion.sql)))
+}
+
+/// Fill in the down section of a single-file migration with a draft downgrade, unless it
+/// already has one
+fn fill_down_section(text: &str) -> anyhow::Result<Option<String>> {
+    let Some((up, down)) = split_sections(text)? else {
+        return Ok(None);
+    };
+    if !strip_comments(&down)?.trim().is_empty() {
+        return Ok(None);
+    }
+    let Some(draft) = draft_downgrade(&up)? else {
+        return Ok(None);
+    };
+    let mut kept = String::new();
+    for line in text.split_inclusive('\n') {
+        kept.push_str(line);
+        if line.trim().eq_ignore_ascii_case(DOWN_MARKER) {
+            break;
+        }
+    }
+    if !kept.ends_with('\n') {
+        kept.push('\n');
+    }
+    Ok(Some(kept + &draft))
+}
+
 #[derive(Debug)]
 pub(crate) struct Migration {
-    pub id: u32,
+    pub id: Version,
     pub label: Option<String>,
     pub upgrade_text: String,
     pub downgrade_text: Option<String>,
 }
 
 impl Migration {
+    /// Hex-encoded SHA-256 of the upgrade text, recorded when the migration is applied so that
+    /// later edits to the file can be detected
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.upgrade_text.as_bytes()))
+    }
+
+    fn parse_sql(s: &str, p: &Path) -> anyhow::Result<String> {
+        strip_comments(s).with_context(|| format!("Could not parse {}", p.display()))
+    }
+
     fn read_sql_from_path(p: &Path) -> anyhow::Result<String> {
-        lazy_static::lazy_static! {
-            static ref ONE_LINE_COMMENT_RE: regex::Regex =
-                regex::Regex::new(r"^-- (.*)$").unwrap();
-            static ref MULTILINE_COMMENT_RE: regex::Regex =
-                regex::Regex::new(r"/\* .* \*/").unwrap();
-            static ref EMPTY_LINE_RE: regex::Regex =
-                regex::Regex::new(r"^\s+\n").unwrap();
-        }
         let s = std::fs::read_to_string(p)?;
-        let s = ONE_LINE_COMMENT_RE.replace_all(&s, "");
-        let s = MULTILINE_COMMENT_RE.replace_all(&s, "");
-        let s = EMPTY_LINE_RE.replace_all(&s, "");
-        Ok(s.to_string())
+        Migration::parse_sql(&s, p)
     }
 
-    fn from_path(id: u32, p: &Path) -> anyhow::Result<Self> {
+    fn from_paths(id: Version, p: &Path, downgrade_p: Option<&Path>) -> anyhow::Result<Self> {
         let upgrade_file = std::fs::read_to_string(p)?;
         lazy_static::lazy_static! {
             static ref LABEL_RE: regex::Regex =
@@ -45,12 +200,25 @@ impl Migration {
             .next()
             .and_then(|first_line| LABEL_RE.captures(first_line))
             .map(|c| c.get(1).unwrap().as_str());
-        let upgrade_text = Migration::read_sql_from_path(p)?;
-        let downgrade_p = p.with_file_name(format!("v{id}_downgrade.sql"));
-        let downgrade_text = if downgrade_p.exists() {
-            Some(Migration::read_sql_from_path(&downgrade_p)?)
-        } else {
-            None
+        let (upgrade_text, downgrade_text) = match split_sections(&upgrade_file)
+            .with_context(|| format!("Could not parse {}", p.display()))?
+        {
+            Some(_) if downgrade_p.is_some() => anyhow::bail!(
+                "{} has {} and {} sections, but there's a separate downgrade file too",
+                p.display(),
+                UP_MARKER,
+                DOWN_MARKER
+            ),
+            Some((up, down)) => {
+                let down = Migration::parse_sql(&down, p)?;
+                // a down section with nothing but comments means the migration is irreversible
+                let down = Some(down).filter(|d| !d.trim().is_empty());
+                (Migration::parse_sql(&up, p)?, down)
+            }
+            None => (
+                Migration::parse_sql(&upgrade_file, p)?,
+                downgrade_p.map(Migration::read_sql_from_path).transpose()?,
+            ),
         };
         debug!("Found upgrade text {upgrade_text:?}");
         debug!("Found downgrade text {downgrade_text:?}");
@@ -63,93 +231,334 @@ impl Migration {
     }
 }
 
+/// Something odd about the migrations directory which doesn't stop migrations from loading
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LoadWarning {
+    /// Migrations numbered `from` to `to` (inclusive) don't exist
+    Gap { from: Version, to: Version },
+    /// A downgrade without a matching upgrade
+    OrphanDowngrade(PathBuf),
+    /// A file which doesn't match any of the naming patterns
+    StrayFile(PathBuf),
+    /// The label in a file's name doesn't match the one in its header
+    LabelMismatch { path: PathBuf, label: String },
+    /// A downgrade whose name has a different label from its upgrade
+    DowngradeMismatch {
+        upgrade: PathBuf,
+        downgrade: PathBuf,
+    },
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadWarning::Gap { from, to } if from == to => {
+                write!(f, "migration {from} is missing")
+            }
+            LoadWarning::Gap { from, to } => write!(f, "migrations {from} to {to} are missing"),
+            LoadWarning::OrphanDowngrade(p) => {
+                write!(
+                    f,
+                    "{} has no matching upgrade; it will never be run",
+                    p.display()
+                )
+            }
+            LoadWarning::StrayFile(p) => write!(
+                f,
+                "{} isn't named like a migration (v{{id}}__{{label}}.sql or v{{id}}__{{label}}.down.sql); it will never be run",
+                p.display()
+            ),
+            LoadWarning::LabelMismatch { path, label } => write!(
+                f,
+                "the name of {} doesn't match its label {:?}",
+                path.display(),
+                label
+            ),
+            LoadWarning::DowngradeMismatch { upgrade, downgrade } => write!(
+                f,
+                "{} is named differently from its upgrade {}",
+                downgrade.display(),
+                upgrade.display()
+            ),
+        }
+    }
+}
+
+/// What a file in the migrations directory is, going by its name
+struct MigrationFile {
+    id: Version,
+    /// The label, as it appears in the name
+    slug: Option<String>,
+    is_downgrade: bool,
+}
+
+impl MigrationFile {
+    /// Files are named `v{version}__{label}.sql` and `v{version}__{label}.down.sql`. Older
+    /// versions of rmmm wrote `v{version}.sql` and `v{version}_downgrade.sql`, which still load.
+    fn from_name(name: &str) -> Option<Self> {
+        lazy_static::lazy_static! {
+            static ref STEM_RE: regex::Regex =
+                regex::Regex::new(r"^v([0-9]+)(?:__([a-z0-9_]+))?$").unwrap();
+            static ref OLD_DOWNGRADE_RE: regex::Regex =
+                regex::Regex::new(r"^v[0-9]+_downgrade\.sql$").unwrap();
+        }
+        // the old suffix only counts without a label, or `v2__fix_downgrade.sql` (generated for
+        // "fix downgrade") would be taken for a downgrade
+        let (stem, is_downgrade) = if let Some(stem) = name.strip_suffix(".down.sql") {
+            (stem, true)
+        } else if OLD_DOWNGRADE_RE.is_match(name) {
+            (name.strip_suffix("_downgrade.sql")?, true)
+        } else {
+            (name.strip_suffix(".sql")?, false)
+        };
+        let caps = STEM_RE.captures(stem)?;
+        Some(MigrationFile {
+            id: Version(caps[1].parse().ok()?),
+            slug: caps.get(2).map(|m| m.as_str().to_string()),
+            is_downgrade,
+        })
+    }
+}
+
+/// Turn a label into something which can go in a file name
+fn slug(label: &str) -> String {
+    label
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .join("_")
+}
+
+/// Add `path` as the file for `id`, refusing to pick between two files with the same id (such
+/// as `v3.sql` and `v03.sql`)
+fn insert_unique(
+    files: &mut BTreeMap<Version, (PathBuf, Option<String>)>,
+    file: MigrationFile,
+    path: PathBuf,
+) -> anyhow::Result<()> {
+    if let Some((existing, _)) = files.get(&file.id) {
+        anyhow::bail!(
+            "{} and {} both have id {}",
+            existing.display(),
+            path.display(),
+            file.id
+        );
+    }
+    files.insert(file.id, (path, file.slug));
+    Ok(())
+}
+
 pub(crate) struct MigrationState {
     root_path: PathBuf,
     pub migrations: Vec<Migration>,
-    next_id: u32,
+    pub warnings: Vec<LoadWarning>,
 }
 
 impl MigrationState {
     pub fn load<P: Into<PathBuf>>(root_path: P) -> anyhow::Result<Self> {
         let root_path = root_path.into();
-        if !root_path.exists() {
+        let migrations_path = root_path.join("migrations");
+        if !migrations_path.exists() {
             return Ok(MigrationState {
                 root_path,
                 migrations: vec![],
-                next_id: 1,
+                warnings: vec![],
             });
         }
-        let migrations = (1..)
-            .map(|id| {
-                let expected_path = root_path.join("migrations").join(format!("v{id}.sql"));
-                if expected_path.exists() {
-                    debug!("Loading migration from {expected_path:?}");
-                    Some(
-                        Migration::from_path(id, &expected_path)
-                            .with_context(|| format!("Could not load migration {id}"))
-                            .unwrap(),
-                    )
-                } else {
-                    None
+        let mut upgrades = BTreeMap::new();
+        let mut downgrades = BTreeMap::new();
+        let mut warnings = vec![];
+        // sorted, so that warnings and errors come out in a stable order
+        let paths = std::fs::read_dir(&migrations_path)
+            .and_then(|entries| {
+                entries
+                    .map(|e| e.map(|e| e.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("Could not read {}", migrations_path.display()))?
+            .into_iter()
+            .sorted();
+        for path in paths {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            // dotfiles such as .gitkeep, and the temporary files `generate` writes
+            if name.starts_with('.') || !path.is_file() {
+                continue;
+            }
+            match MigrationFile::from_name(&name) {
+                Some(file) if file.is_downgrade => insert_unique(&mut downgrades, file, path)?,
+                Some(file) => insert_unique(&mut upgrades, file, path)?,
+                None => warnings.push(LoadWarning::StrayFile(path)),
+            }
+        }
+        for (id, (path, slug)) in &downgrades {
+            match upgrades.get(id) {
+                None => warnings.push(LoadWarning::OrphanDowngrade(path.clone())),
+                Some((upgrade, Some(upgrade_slug)))
+                    if slug.as_ref().is_some_and(|s| s != upgrade_slug) =>
+                {
+                    warnings.push(LoadWarning::DowngradeMismatch {
+                        upgrade: upgrade.clone(),
+                        downgrade: path.clone(),
+                    })
                 }
+                Some(_) => {}
+            }
+        }
+        // only sequential versions are expected to be contiguous
+        let mut expected = 1;
+        for id in upgrades.keys().filter(|id| !id.is_timestamp()) {
+            if id.0 > expected {
+                warnings.push(LoadWarning::Gap {
+                    from: Version(expected),
+                    to: Version(id.0 - 1),
+                });
+            }
+            expected = id.0 + 1;
+        }
+        let migrations = upgrades
+            .into_iter()
+            .map(|(id, (path, file_slug))| {
+                debug!("Loading migration from {path:?}");
+                let downgrade = downgrades.get(&id).map(|(p, _)| p.as_path());
+                let mut migration = Migration::from_paths(id, &path, downgrade)
+                    .with_context(|| format!("Could not load migration {id}"))?;
+                match (&migration.label, file_slug) {
+                    (Some(label), Some(file_slug)) if slug(label) != file_slug => {
+                        warnings.push(LoadWarning::LabelMismatch {
+                            path,
+                            label: label.clone(),
+                        })
+                    }
+                    // the name is all there is to go on
+                    (None, Some(file_slug)) => migration.label = Some(file_slug.replace('_', " ")),
+                    _ => {}
+                }
+                Ok(migration)
             })
-            .while_some()
-            .collect::<Vec<_>>();
-        let next_id = migrations.iter().map(|m| m.id).next_back().unwrap_or(0) + 1;
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for warning in &warnings {
+            warn!("{warning}");
+        }
         Ok(MigrationState {
             root_path,
             migrations,
-            next_id,
+            warnings,
         })
     }
 
-    pub fn generate(&self, label: &str) -> anyhow::Result<()> {
+    /// Write a new migration, returning the paths of the files written
+    pub fn generate(&self, new: &NewMigration) -> anyhow::Result<Vec<PathBuf>> {
         let migrations_path = self.root_path.join("migrations");
         std::fs::create_dir_all(&migrations_path)?;
-        let next_file = format!("v{0}.sql", self.next_id);
-        let f = tempfile::Builder::new()
+        let next_id = self.highest_id().next(new.versioning, chrono::Utc::now());
+        let stem = match slug(&new.label) {
+            slug if slug.is_empty() => format!("v{next_id}"),
+            slug => format!("v{next_id}__{slug}"),
+        };
+        let (upgrade_sql, downgrade_sql) = match &new.template {
+            Some(template) => {
+                let mut variables = new.variables.clone();
+                variables.insert("id".to_string(), next_id.to_string());
+                variables.insert("label".to_string(), new.label.clone());
+                let (upgrade, downgrade) = template.render(&variables)?;
+                (
+                    new.upgrade_sql.clone().or(Some(upgrade)),
+                    new.downgrade_sql.clone().or(downgrade),
+                )
+            }
+            None => (new.upgrade_sql.clone(), new.downgrade_sql.clone()),
+        };
+        let has_upgrade = upgrade_sql.is_some();
+        let upgrade_sql = upgrade_sql.as_deref().unwrap_or(UPGRADE_PLACEHOLDER);
+        let mut contents = format!("/* rmmm migration v{next_id} - {} */\n", new.label);
+        let mut downgrade_contents = None;
+        match new.layout {
+            Layout::Separate => {
+                contents.push_str(&format!("\n{}\n", upgrade_sql.trim_end()));
+                match &downgrade_sql {
+                    Some(sql) => downgrade_contents = Some(format!("{}\n", sql.trim_end())),
+                    // only a reminder for whoever fills in the placeholder
+                    None if has_upgrade => {}
+                    None => contents.push_str(&format!(
+                        "-- Create {0}/{stem}.down.sql to make this migraiton reversible\n",
+                        migrations_path.to_string_lossy(),
+                    )),
+                }
+            }
+            Layout::SingleFile => {
+                let downgrade_sql = downgrade_sql.as_deref().unwrap_or(DOWNGRADE_PLACEHOLDER);
+                contents.push_str(&format!(
+                    "\n{UP_MARKER}\n{}\n\n{DOWN_MARKER}\n{}\n",
+                    upgrade_sql.trim_end(),
+                    downgrade_sql.trim_end()
+                ));
+            }
+        }
+        let mut f = tempfile::Builder::new()
             .suffix(".sql")
             .tempfile_in(&migrations_path)?;
-        {
-            let mut f = f.as_file();
-            writeln!(f, "/* rmmm migration v{0} - {1} */", self.next_id, label)?;
-            writeln!(
-                f,
-                "\n-- Delete this comment and put your migration here. Blank lines and comments are ignored."
-            )?;
-            writeln!(
-                f,
-                "-- Create {0}/v{1}_downgrade.sql to make this migraiton reversible",
-                migrations_path.to_string_lossy(),
-                self.next_id
-            )?;
-            f.sync_all()?;
-        }
-        let editor = env::var("EDITOR").unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
-        let status = std::process::Command::new(editor)
-            .arg(f.path())
-            .status()
-            .expect(
-                "Could not invoke editor on migration; try setting $EDITOR to something useful",
-            );
-        if status.success() {
-            f.persist_noclobber(migrations_path.join(next_file))?;
-        } else {
-            anyhow::bail!("Editor exited non-0, discarding migration");
+        f.write_all(contents.as_bytes())?;
+        f.as_file().sync_all()?;
+        if new.edit {
+            let editor = env::var("EDITOR").unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
+            run_editor(&editor, f.path())?;
         }
-        Ok(())
+        // drafted after editing, so that it undoes whatever was written in the editor
+        if new.suggest_downgrade && downgrade_sql.is_none() {
+            let written = std::fs::read_to_string(f.path())?;
+            match new.layout {
+                Layout::Separate => downgrade_contents = draft_downgrade(&written)?,
+                Layout::SingleFile => {
+                    if let Some(contents) = fill_down_section(&written)? {
+                        std::fs::write(f.path(), contents)?;
+                    }
+                }
+            }
+        }
+        // both files are written out before either is put in place
+        let downgrade_file = match downgrade_contents {
+            Some(contents) => {
+                let mut d = tempfile::Builder::new()
+                    .suffix(".sql")
+                    .tempfile_in(&migrations_path)?;
+                d.write_all(contents.as_bytes())?;
+                d.as_file().sync_all()?;
+                Some(d)
+            }
+            None => None,
+        };
+        let mut written = vec![migrations_path.join(format!("{stem}.sql"))];
+        f.persist_noclobber(&written[0])
+            .with_context(|| format!("Could not write {}", written[0].display()))?;
+        if let Some(d) = downgrade_file {
+            let path = migrations_path.join(format!("{stem}.down.sql"));
+            if let Err(e) = d.persist_noclobber(&path) {
+                // half a migration would take up the version
+                if let Err(e) = std::fs::remove_file(&written[0]) {
+                    warn!("Could not remove {}: {e}", written[0].display());
+                }
+                return Err(e).with_context(|| format!("Could not write {}", path.display()));
+            }
+            written.push(path);
+        }
+        Ok(written)
     }
 
-    pub fn migrations_by_id(&self) -> BTreeMap<u32, &Migration> {
+    pub fn template(&self, name: &str) -> anyhow::Result<Template> {
+        Template::load(&self.root_path, name)
+    }
+
+    pub fn migrations_by_id(&self) -> BTreeMap<Version, &Migration> {
         self.migrations.iter().map(|m| (m.id, m)).collect()
     }
 
-    pub fn all_ids(&self) -> BTreeSet<u32> {
+    pub fn all_ids(&self) -> BTreeSet<Version> {
         self.migrations.iter().map(|m| m.id).collect()
     }
 
-    pub fn highest_id(&self) -> u32 {
-        self.next_id - 1
+    /// The highest version on disk, or 0 if there are no migrations
+    pub fn highest_id(&self) -> Version {
+        self.migrations.last().map(|m| m.id).unwrap_or_default()
     }
 
     pub fn write_schema(&self, schema: &str) -> anyhow::Result<()> {
@@ -166,14 +575,19 @@ impl MigrationState {
 
 #[cfg(test)]
 mod tests {
-    use super::MigrationState;
+    use std::collections::BTreeSet;
+
+    use super::{
+        Layout, LoadWarning, MigrationState, NewMigration, run_editor, slug, split_sections,
+    };
+    use crate::version::Version;
 
     #[test]
     fn test_basic_flow() {
         let wd = tempfile::TempDir::new().unwrap();
         let uut = MigrationState::load(wd.path()).expect("Should load empty dir");
         assert_eq!(uut.all_ids().len(), 0);
-        assert_eq!(uut.highest_id(), 0);
+        assert_eq!(uut.highest_id(), Version(0));
         assert_eq!(uut.migrations_by_id().len(), 0);
     }
 
@@ -187,7 +601,366 @@ mod tests {
         std::fs::write(wd.path().join("migrations").join("v2.sql"), v2).unwrap();
         let uut = MigrationState::load(wd.path()).expect("Should load full dir");
         assert_eq!(uut.all_ids().len(), 2);
-        assert_eq!(uut.highest_id(), 2);
+        assert_eq!(uut.highest_id(), Version(2));
         assert_eq!(uut.migrations_by_id().len(), 2);
     }
+
+    #[test]
+    fn test_gaps_and_stray_files() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        for name in [
+            "v1.sql",
+            "v4.sql",
+            "v4_downgrade.sql",
+            "v5_downgrade.sql",
+            "V6.SQL",
+            ".gitkeep",
+        ] {
+            std::fs::write(migrations.join(name), "SELECT 1").unwrap();
+        }
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(uut.all_ids(), BTreeSet::from([Version(1), Version(4)]));
+        assert_eq!(uut.highest_id(), Version(4));
+        assert!(uut.migrations_by_id()[&Version(4)].downgrade_text.is_some());
+        assert_eq!(
+            uut.warnings,
+            vec![
+                LoadWarning::StrayFile(migrations.join("V6.SQL")),
+                LoadWarning::OrphanDowngrade(migrations.join("v5_downgrade.sql")),
+                LoadWarning::Gap {
+                    from: Version(2),
+                    to: Version(3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timestamp_versions() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        // a project which switched to timestamps after two sequential migrations
+        for name in [
+            "v1.sql",
+            "v2.sql",
+            "v20261016183000__add_users.sql",
+            "v20261016183000__add_users.down.sql",
+            "v20261017090000__add_orders.sql",
+        ] {
+            std::fs::write(migrations.join(name), "SELECT 1").unwrap();
+        }
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(
+            uut.all_ids().into_iter().collect::<Vec<_>>(),
+            vec![
+                Version(1),
+                Version(2),
+                Version(20261016183000),
+                Version(20261017090000)
+            ]
+        );
+        assert!(
+            uut.migrations_by_id()[&Version(20261016183000)]
+                .downgrade_text
+                .is_some()
+        );
+        assert_eq!(uut.warnings, vec![]);
+        assert_eq!(slug("Add users (and admins)!"), "add_users_and_admins");
+    }
+
+    #[test]
+    fn test_descriptive_names() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        for (name, contents) in [
+            ("v1.sql", "SELECT 1"),
+            ("v1_downgrade.sql", "SELECT 1"),
+            (
+                "v2__add_orders_index.sql",
+                "/* rmmm migration v2 - Add orders index */\nSELECT 1",
+            ),
+            ("v2__add_orders_index.down.sql", "SELECT 1"),
+            (
+                "v3__add_users.sql",
+                "/* rmmm migration v3 - Add admins */\nSELECT 1",
+            ),
+            ("v3__add_admins.down.sql", "SELECT 1"),
+            ("v4__add_carts.sql", "SELECT 1"),
+        ] {
+            std::fs::write(migrations.join(name), contents).unwrap();
+        }
+        let uut = MigrationState::load(wd.path()).unwrap();
+        let by_id = uut.migrations_by_id();
+        assert!(by_id[&Version(1)].downgrade_text.is_some());
+        assert!(by_id[&Version(2)].downgrade_text.is_some());
+        assert_eq!(
+            by_id[&Version(2)].label.as_deref(),
+            Some("Add orders index")
+        );
+        assert_eq!(by_id[&Version(4)].label.as_deref(), Some("add carts"));
+        assert_eq!(
+            uut.warnings,
+            vec![
+                LoadWarning::DowngradeMismatch {
+                    upgrade: migrations.join("v3__add_users.sql"),
+                    downgrade: migrations.join("v3__add_admins.down.sql"),
+                },
+                LoadWarning::LabelMismatch {
+                    path: migrations.join("v3__add_users.sql"),
+                    label: "Add admins".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_ending_in_downgrade() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let mut new = NewMigration {
+            label: "fix downgrade".to_string(),
+            ..NewMigration::default()
+        };
+        MigrationState::load(wd.path())
+            .unwrap()
+            .generate(&new)
+            .unwrap();
+        new.label = "third".to_string();
+        let written = MigrationState::load(wd.path())
+            .unwrap()
+            .generate(&new)
+            .unwrap();
+        let migrations = wd.path().join("migrations");
+        assert_eq!(written, vec![migrations.join("v2__third.sql")]);
+
+        // nor do labels with a single underscore, which rmmm never wrote
+        std::fs::write(migrations.join("v3_downgrade_users.sql"), "SELECT 1").unwrap();
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(
+            uut.all_ids().into_iter().collect::<Vec<_>>(),
+            vec![Version(1), Version(2)]
+        );
+        assert!(uut.migrations_by_id()[&Version(1)].downgrade_text.is_none());
+        assert_eq!(
+            uut.warnings,
+            vec![LoadWarning::StrayFile(
+                migrations.join("v3_downgrade_users.sql")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_split_sections() {
+        let text = "/* header */\n-- +up\nCREATE TABLE a(id INT);\n-- +down\nDROP TABLE a;\n";
+        let (up, down) = split_sections(text).unwrap().unwrap();
+        assert_eq!(up, "/* header */\n\nCREATE TABLE a(id INT);\n\n\n");
+        assert_eq!(down, "\n\n\n\nDROP TABLE a;\n");
+        assert!(split_sections("CREATE TABLE a(id INT);").unwrap().is_none());
+        assert!(split_sections("-- +down\n-- +up\n").is_err());
+        assert!(split_sections("-- +up\n-- +up\n").is_err());
+    }
+
+    #[test]
+    fn test_single_file() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        let path = migrations.join("v1__add_a.sql");
+        std::fs::write(
+            &path,
+            "/* rmmm migration v1 - add a */\n-- +up\nCREATE TABLE a(id INT);\n-- +down\nDROP TABLE a;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            migrations.join("v2__add_b.sql"),
+            "-- +up\nCREATE TABLE b(id INT);\n-- +down\n-- can't be undone\n",
+        )
+        .unwrap();
+        let uut = MigrationState::load(wd.path()).unwrap();
+        let by_id = uut.migrations_by_id();
+        let migration = by_id[&Version(1)];
+        assert_eq!(migration.label.as_deref(), Some("add a"));
+        assert_eq!(migration.upgrade_text, "\n\nCREATE TABLE a(id INT);");
+        assert_eq!(
+            migration.downgrade_text.as_deref(),
+            Some("\n\n\n\nDROP TABLE a;")
+        );
+        assert!(by_id[&Version(2)].downgrade_text.is_none());
+
+        // editing the down section doesn't count as modifying the migration
+        let checksum = migration.checksum();
+        std::fs::write(
+            &path,
+            "/* rmmm migration v1 - add a */\n-- +up\nCREATE TABLE a(id INT);\n-- +down\nDROP TABLE IF EXISTS a;\n",
+        )
+        .unwrap();
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(uut.migrations_by_id()[&Version(1)].checksum(), checksum);
+
+        std::fs::write(migrations.join("v1__add_a.down.sql"), "DROP TABLE a;").unwrap();
+        assert!(MigrationState::load(wd.path()).is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let uut = MigrationState::load(wd.path()).unwrap();
+        let mut new = NewMigration {
+            label: "Add users".to_string(),
+            upgrade_sql: Some("CREATE TABLE users(id INT);\n".to_string()),
+            downgrade_sql: Some("DROP TABLE users;".to_string()),
+            ..NewMigration::default()
+        };
+        let written = uut.generate(&new).unwrap();
+        let migrations = wd.path().join("migrations");
+        assert_eq!(
+            written,
+            vec![
+                migrations.join("v1__add_users.sql"),
+                migrations.join("v1__add_users.down.sql")
+            ]
+        );
+
+        new.label = "add orders".to_string();
+        new.layout = Layout::SingleFile;
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(
+            uut.generate(&new).unwrap(),
+            vec![migrations.join("v2__add_orders.sql")]
+        );
+
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert_eq!(uut.warnings, vec![]);
+        for migration in &uut.migrations {
+            assert_eq!(migration.upgrade_text.trim(), "CREATE TABLE users(id INT);");
+            assert_eq!(
+                migration.downgrade_text.as_deref().map(str::trim),
+                Some("DROP TABLE users;")
+            );
+        }
+        assert_eq!(
+            uut.migrations_by_id()[&Version(2)].label.as_deref(),
+            Some("add orders")
+        );
+    }
+
+    #[test]
+    fn test_generate_is_all_or_nothing() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        // an orphaned downgrade in the way
+        std::fs::write(migrations.join("v1__add_users.down.sql"), "DROP TABLE x;").unwrap();
+        let new = NewMigration {
+            label: "add users".to_string(),
+            upgrade_sql: Some("CREATE TABLE users(id INT);".to_string()),
+            downgrade_sql: Some("DROP TABLE users;".to_string()),
+            ..NewMigration::default()
+        };
+        let uut = MigrationState::load(wd.path()).unwrap();
+        assert!(uut.generate(&new).is_err());
+        let names = std::fs::read_dir(&migrations)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["v1__add_users.down.sql"]);
+    }
+
+    #[test]
+    fn test_generate_with_downgrade() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let mut new = NewMigration {
+            label: "add users".to_string(),
+            upgrade_sql: Some(
+                "CREATE TABLE users(id INT);\nUPDATE accounts SET migrated = 1;\n".to_string(),
+            ),
+            suggest_downgrade: true,
+            ..NewMigration::default()
+        };
+        for layout in [Layout::Separate, Layout::SingleFile] {
+            new.layout = layout;
+            MigrationState::load(wd.path())
+                .unwrap()
+                .generate(&new)
+                .unwrap();
+        }
+        // no upgrade, so nothing to reverse
+        new.upgrade_sql = None;
+        MigrationState::load(wd.path())
+            .unwrap()
+            .generate(&new)
+            .unwrap();
+
+        let uut = MigrationState::load(wd.path()).unwrap();
+        for migration in &uut.migrations[..2] {
+            assert_eq!(
+                migration.downgrade_text.as_deref().map(str::trim),
+                Some("DROP TABLE users;")
+            );
+        }
+        let migrations = wd.path().join("migrations");
+        for name in ["v1__add_users.down.sql", "v2__add_users.sql"] {
+            let text = std::fs::read_to_string(migrations.join(name)).unwrap();
+            assert!(
+                text.contains("-- UPDATE accounts SET migrated = 1\n"),
+                "{text}"
+            );
+        }
+        assert_eq!(uut.migrations[2].downgrade_text, None);
+    }
+
+    #[test]
+    fn test_run_editor() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        run_editor("sh -c 'echo edited > \"$0\"'", file.path()).unwrap();
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "edited\n");
+        assert!(run_editor("false", file.path()).is_err());
+        assert!(run_editor("rmmm-no-such-editor --wait", file.path()).is_err());
+        assert!(run_editor("", file.path()).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_ids() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        std::fs::write(migrations.join("v3.sql"), "SELECT 1").unwrap();
+        std::fs::write(migrations.join("v03.sql"), "SELECT 1").unwrap();
+        let err = MigrationState::load(wd.path()).err().unwrap();
+        assert!(err.to_string().contains("both have id 3"), "{err}");
+    }
+
+    #[test]
+    fn test_comments_stripped() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let v1 = "/* rmmm migration v1 - test comments */\n\
+            -- Delete this comment\n\
+            # and this one\n\
+            INSERT INTO t(v) VALUES ('-- kept'), ('/* kept */'); -- not kept\n";
+        std::fs::create_dir_all(wd.path().join("migrations")).unwrap();
+        std::fs::write(wd.path().join("migrations").join("v1.sql"), v1).unwrap();
+        let uut = MigrationState::load(wd.path()).expect("Should load full dir");
+        let migration = uut.migrations_by_id()[&Version(1)];
+        assert_eq!(migration.label.as_deref(), Some("test comments"));
+        assert_eq!(
+            migration.upgrade_text,
+            "\n\n\nINSERT INTO t(v) VALUES ('-- kept'), ('/* kept */');"
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let migrations = wd.path().join("migrations");
+        std::fs::create_dir_all(&migrations).unwrap();
+        std::fs::write(migrations.join("v1.sql"), "CREATE TABLE a(id INT);").unwrap();
+        let before = MigrationState::load(wd.path()).unwrap().migrations[0].checksum();
+        assert_eq!(before.len(), 64);
+        std::fs::write(migrations.join("v1.sql"), "CREATE TABLE b(id INT);").unwrap();
+        let after = MigrationState::load(wd.path()).unwrap().migrations[0].checksum();
+        assert_ne!(before, after);
+    }
 }
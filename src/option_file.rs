@@ -0,0 +1,336 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use itertools::Itertools;
+use log::{debug, warn};
+
+use crate::config::{Environment, lookup};
+
+/// Files read when `--defaults-file` isn't passed, in the same order as the mysql client
+const DEFAULT_FILES: &[&str] = &["/etc/my.cnf", "/etc/mysql/my.cnf", "~/.my.cnf"];
+
+/// Option file groups which apply to rmmm; `--defaults-group-suffix` adds suffixed versions
+const GROUPS: &[&str] = &["client", "rmmm"];
+
+// MySQL gives up on deeper nesting too
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Connection settings read from MySQL option files such as `~/.my.cnf`
+#[derive(Default, PartialEq, Eq)]
+pub(crate) struct ClientOptions {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub socket: Option<String>,
+    pub database: Option<String>,
+}
+
+/// Expand a leading `~/` the way a shell would
+fn expand_home(path: &str) -> Option<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => std::env::var_os("HOME").map(|home| Path::new(&home).join(rest)),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Unquote a value and process its escape sequences, as described in the MySQL manual
+fn parse_value(raw: &str) -> anyhow::Result<String> {
+    let raw = raw.trim();
+    let (body, quote) = match raw.chars().next() {
+        Some(q @ ('"' | '\'')) => (&raw[1..], Some(q)),
+        // unquoted values end at a comment
+        _ => (raw.split('#').next().unwrap_or_default().trim_end(), None),
+    };
+    let mut value = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('b') => value.push('\x08'),
+                Some('t') => value.push('\t'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('s') => value.push(' '),
+                Some(other) => value.push(other),
+                None => value.push('\\'),
+            },
+            c if Some(c) == quote => return Ok(value),
+            c => value.push(c),
+        }
+    }
+    if quote.is_some() {
+        anyhow::bail!("unterminated quoted value {}", raw);
+    }
+    Ok(value)
+}
+
+/// Problems with option files which weren't named explicitly are only worth a warning
+fn tolerate(result: anyhow::Result<()>, strict: bool) -> anyhow::Result<()> {
+    match result {
+        Err(e) if !strict => {
+            warn!("{e:#}; skipping it");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+impl ClientOptions {
+    /// Read the option files selected by `--defaults-file`, `--defaults-group-suffix` and
+    /// `--no-defaults`
+    pub fn load(matches: &clap::ArgMatches, env: &Environment) -> anyhow::Result<Self> {
+        let mut options = ClientOptions::default();
+        if matches.is_present("no_defaults") {
+            return Ok(options);
+        }
+        let mut groups = GROUPS.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        if let Some(suffix) = lookup(
+            matches,
+            "defaults_group_suffix",
+            env.defaults_group_suffix.clone(),
+        ) {
+            groups.extend(GROUPS.iter().map(|g| format!("{g}{suffix}")));
+        }
+        match lookup(matches, "defaults_file", env.defaults_file.clone()) {
+            Some(path) => {
+                let path = expand_home(&path)
+                    .ok_or_else(|| anyhow::anyhow!("cannot expand {} without $HOME", path))?;
+                options.read_file(&path, &groups, 0, true)?
+            }
+            None => {
+                // nobody asked for these, so they mustn't get in the way of a complete URL or DSN
+                for path in DEFAULT_FILES.iter().filter_map(|p| expand_home(p)) {
+                    if path.exists() {
+                        tolerate(options.read_file(&path, &groups, 0, false), false)?;
+                    }
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Read `path` and anything it includes. Unless `strict` is set, unreadable includes are
+    /// skipped with a warning.
+    fn read_file(
+        &mut self,
+        path: &Path,
+        groups: &[String],
+        depth: usize,
+        strict: bool,
+    ) -> anyhow::Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            anyhow::bail!("too many nested !include directives at {}", path.display());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            if metadata.permissions().mode() & 0o002 != 0 {
+                warn!("ignoring world-writable option file {}", path.display());
+                return Ok(());
+            }
+        }
+        debug!("Reading options from {path:?}");
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        self.parse(&contents, path, groups, depth, strict)
+            .with_context(|| format!("Could not parse {}", path.display()))
+    }
+
+    fn parse(
+        &mut self,
+        contents: &str,
+        path: &Path,
+        groups: &[String],
+        depth: usize,
+        strict: bool,
+    ) -> anyhow::Result<()> {
+        let mut active = false;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(included) = line.strip_prefix("!includedir") {
+                let dir = path.parent().unwrap_or(Path::new("")).join(included.trim());
+                let files = match std::fs::read_dir(&dir) {
+                    Ok(entries) => entries
+                        .filter_map(|e| e.ok().map(|e| e.path()))
+                        .filter(|p| p.extension().is_some_and(|e| e == "cnf"))
+                        .sorted()
+                        .collect(),
+                    Err(e) => {
+                        let e = anyhow::Error::new(e)
+                            .context(format!("Could not read {}", dir.display()));
+                        tolerate(Err(e), strict)?;
+                        vec![]
+                    }
+                };
+                for file in files {
+                    tolerate(self.read_file(&file, groups, depth + 1, strict), strict)?;
+                }
+            } else if let Some(included) = line.strip_prefix("!include") {
+                let file = path.parent().unwrap_or(Path::new("")).join(included.trim());
+                tolerate(self.read_file(&file, groups, depth + 1, strict), strict)?;
+            } else if let Some(group) = line.strip_prefix('[') {
+                let group = group
+                    .strip_suffix(']')
+                    .ok_or_else(|| anyhow::anyhow!("invalid group on line {}", number + 1))?;
+                active = groups.iter().any(|g| g.eq_ignore_ascii_case(group.trim()));
+            } else if active {
+                let (key, value) = match line.split_once('=') {
+                    Some((key, value)) => (key.trim(), parse_value(value)?),
+                    None => (line, String::new()),
+                };
+                let key = key.replace('_', "-").to_ascii_lowercase();
+                let key = key.strip_prefix("loose-").unwrap_or(&key);
+                match key {
+                    "user" => self.user = Some(value),
+                    "password" => self.password = Some(value),
+                    "host" => self.host = Some(value),
+                    "port" => {
+                        self.port = Some(
+                            value
+                                .parse()
+                                .with_context(|| format!("invalid port on line {}", number + 1))?,
+                        )
+                    }
+                    "socket" => self.socket = Some(value),
+                    "database" => self.database = Some(value),
+                    other => debug!("ignoring option {other} which rmmm doesn't use"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in whatever the URL or DSN left out. Only credentials and the database are merged;
+    /// the URL or DSN always names the server, so the host, port and socket are only used when
+    /// neither is given.
+    pub fn merge(self, opts: Option<mysql::Opts>) -> Option<mysql::Opts> {
+        match opts {
+            Some(opts) => {
+                let user = opts.get_user().map(str::to_owned).or(self.user);
+                let pass = opts.get_pass().map(str::to_owned).or(self.password);
+                let db_name = opts.get_db_name().map(str::to_owned).or(self.database);
+                Some(
+                    mysql::OptsBuilder::from_opts(opts)
+                        .user(user)
+                        .pass(pass)
+                        .db_name(db_name)
+                        .into(),
+                )
+            }
+            None if self == ClientOptions::default() => None,
+            None => {
+                let mut builder = mysql::OptsBuilder::new()
+                    .user(self.user)
+                    .pass(self.password)
+                    .db_name(self.database)
+                    .socket(self.socket);
+                if let Some(host) = self.host {
+                    builder = builder.ip_or_hostname(Some(host));
+                }
+                if let Some(port) = self.port {
+                    builder = builder.tcp_port(port);
+                }
+                Some(builder.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ClientOptions, parse_value};
+
+    const EXAMPLE: &str = r#"
+        # a comment
+        [mysqld]
+        user = mysql
+
+        [client]
+        user = operator
+        password = "se#cret\"s"
+        port=3307
+
+        [rmmm_prod]
+        loose_database = app  # trailing comment
+        host = db.internal
+    "#;
+
+    fn parse(groups: &[&str]) -> ClientOptions {
+        let groups = groups.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        let mut options = ClientOptions::default();
+        options
+            .parse(EXAMPLE, Path::new("my.cnf"), &groups, 0, true)
+            .unwrap();
+        options
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value(" plain ").unwrap(), "plain");
+        assert_eq!(parse_value("plain # comment").unwrap(), "plain");
+        assert_eq!(
+            parse_value(r#""has # and \"quotes\"""#).unwrap(),
+            "has # and \"quotes\""
+        );
+        assert_eq!(parse_value(r"'a\sb\\c'").unwrap(), r"a b\c");
+        assert!(parse_value("'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse(&["client", "rmmm"]);
+        assert_eq!(options.user.as_deref(), Some("operator"));
+        assert_eq!(options.password.as_deref(), Some("se#cret\"s"));
+        assert_eq!(options.port, Some(3307));
+        assert_eq!(options.database, None);
+
+        let options = parse(&["client", "rmmm", "client_prod", "rmmm_prod"]);
+        assert_eq!(options.database.as_deref(), Some("app"));
+        assert_eq!(options.host.as_deref(), Some("db.internal"));
+    }
+
+    #[test]
+    fn test_missing_includes() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let path = wd.path().join("my.cnf");
+        std::fs::write(
+            &path,
+            "!includedir conf.d\n!include missing.cnf\n[client]\nuser = operator\n",
+        )
+        .unwrap();
+        let groups = vec!["client".to_string()];
+        let mut options = ClientOptions::default();
+        options.read_file(&path, &groups, 0, false).unwrap();
+        assert_eq!(options.user.as_deref(), Some("operator"));
+        assert!(
+            ClientOptions::default()
+                .read_file(&path, &groups, 0, true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let options = parse(&["client", "rmmm", "rmmm_prod"]);
+        let explicit = mysql::Opts::from_url("mysql://admin@localhost").unwrap();
+        let merged = options.merge(Some(explicit)).unwrap();
+        assert_eq!(merged.get_user(), Some("admin"));
+        assert_eq!(merged.get_pass(), Some("se#cret\"s"));
+        assert_eq!(merged.get_db_name(), Some("app"));
+        assert_eq!(merged.get_ip_or_hostname(), "localhost");
+
+        let merged = parse(&["client", "rmmm_prod"]).merge(None).unwrap();
+        assert_eq!(merged.get_ip_or_hostname(), "db.internal");
+        assert_eq!(merged.get_tcp_port(), 3307);
+
+        assert!(ClientOptions::default().merge(None).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+use tabled::Tabled;
+
+/// Bumped whenever a field is removed or changes meaning in JSON output; adding fields doesn't
+/// count
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+/// How to print results, as selected by `--output`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Table,
+    Json,
+    /// Tab-separated, without headers
+    Plain,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "plain" => Ok(OutputFormat::Plain),
+            other => anyhow::bail!("unknown output format {}", other),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub const VALUES: [&'static str; 3] = ["table", "json", "plain"];
+
+    pub fn from_matches(matches: &clap::ArgMatches) -> Self {
+        matches
+            .value_of("output")
+            .and_then(|o| o.parse().ok())
+            .unwrap_or(OutputFormat::Table)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    schema_version: u32,
+    kind: &'a str,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+/// Format `data` as a JSON document, tagged with the schema version and what kind of document
+/// it is
+fn to_json<T: Serialize>(kind: &str, data: &T) -> anyhow::Result<String> {
+    let envelope = Envelope {
+        schema_version: SCHEMA_VERSION,
+        kind,
+        data,
+    };
+    Ok(serde_json::to_string_pretty(&envelope)?)
+}
+
+pub(crate) fn print_json<T: Serialize>(kind: &str, data: &T) -> anyhow::Result<()> {
+    println!("{}", to_json(kind, data)?);
+    Ok(())
+}
+
+/// Print the first `columns` columns of each row, separated by tabs
+pub(crate) fn print_plain<T: Tabled>(rows: &[T], columns: usize) {
+    for row in rows {
+        let fields = row.fields();
+        let fields = &fields[..columns.min(fields.len())];
+        // tabs and newlines would break up the columns or rows
+        let fields = fields
+            .iter()
+            .map(|f| f.replace(['\t', '\n'], " "))
+            .collect::<Vec<_>>();
+        println!("{}", fields.join("\t"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::{OutputFormat, to_json};
+
+    #[test]
+    fn test_parse() {
+        for value in OutputFormat::VALUES {
+            assert!(value.parse::<OutputFormat>().is_ok());
+        }
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        #[derive(Serialize)]
+        struct Example {
+            migrations: Vec<u32>,
+        }
+        let json: serde_json::Value = serde_json::from_str(
+            &to_json(
+                "status",
+                &Example {
+                    migrations: vec![1, 2],
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"schema_version": 1, "kind": "status", "migrations": [1, 2]})
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+
+/// Variables whose effective values `status -v` always shows, whether or not they're configured
+pub(crate) const REPORTED_VARIABLES: &[&str] = &[
+    "sql_mode",
+    "time_zone",
+    "foreign_key_checks",
+    "lock_wait_timeout",
+    "innodb_lock_wait_timeout",
+];
+
+// system variable names are case-insensitive
+static NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^[a-z_][a-z0-9_]*$").unwrap());
+
+/// Whether `name` looks like a MySQL system variable, and so is safe to interpolate into `SET`
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    NAME_REGEX.is_match(name)
+}
+
+/// The value of a session system variable, as written in rmmm.toml or on the command line
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum SessionValue {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl fmt::Display for SessionValue {
+    /// Formats the value as a SQL literal
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionValue::Bool(true) => write!(f, "ON"),
+            SessionValue::Bool(false) => write!(f, "OFF"),
+            SessionValue::Integer(i) => write!(f, "{i}"),
+            SessionValue::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+        }
+    }
+}
+
+impl FromStr for SessionValue {
+    type Err = std::convert::Infallible;
+
+    /// On the command line, anything which isn't an integer is a string
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(i) => SessionValue::Integer(i),
+            Err(_) => SessionValue::String(s.to_string()),
+        })
+    }
+}
+
+impl SessionValue {
+    /// Parse a value written as SQL, the way go-sql-driver DSNs give system variables: quoted
+    /// strings are unquoted, and anything else is read as on the command line
+    pub fn from_sql(s: &str) -> Self {
+        for quote in ['\'', '"'] {
+            if let Some(inner) = s
+                .strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+            {
+                let doubled = format!("{quote}{quote}");
+                return SessionValue::String(inner.replace(&doubled, &quote.to_string()));
+            }
+        }
+        s.parse().unwrap()
+    }
+}
+
+/// Parse a `--session-var NAME=VALUE` argument
+pub(crate) fn parse_assignment(s: &str) -> anyhow::Result<(String, SessionValue)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("session variable {:?} must look like NAME=VALUE", s))?;
+    Ok((name.trim().to_ascii_lowercase(), value.trim().parse()?))
+}
+
+/// Build the `SET SESSION` statements to run on each new connection
+pub(crate) fn set_statements(
+    variables: &BTreeMap<String, SessionValue>,
+) -> anyhow::Result<Vec<String>> {
+    variables
+        .iter()
+        .map(|(name, value)| {
+            if !is_valid_name(name) {
+                anyhow::bail!("invalid session variable name {:?}", name);
+            }
+            if matches!(value, SessionValue::String(s) if s.contains('\\')) {
+                anyhow::bail!(
+                    "the value of session variable {} may not contain backslashes",
+                    name
+                );
+            }
+            Ok(format!(
+                "SET SESSION {} = {value}",
+                name.to_ascii_lowercase()
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{SessionValue, parse_assignment, set_statements};
+
+    #[test]
+    fn test_from_sql() {
+        assert_eq!(
+            SessionValue::from_sql("'it''s'"),
+            SessionValue::String("it's".to_string())
+        );
+        assert_eq!(
+            SessionValue::from_sql("\"TRADITIONAL\""),
+            SessionValue::String("TRADITIONAL".to_string())
+        );
+        assert_eq!(SessionValue::from_sql("1"), SessionValue::Integer(1));
+        assert_eq!(
+            SessionValue::from_sql("READ-COMMITTED"),
+            SessionValue::String("READ-COMMITTED".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            parse_assignment("foreign_key_checks=0").unwrap(),
+            ("foreign_key_checks".to_string(), SessionValue::Integer(0))
+        );
+        assert_eq!(
+            parse_assignment("time_zone=+00:00").unwrap(),
+            (
+                "time_zone".to_string(),
+                SessionValue::String("+00:00".to_string())
+            )
+        );
+        assert!(parse_assignment("time_zone").is_err());
+        assert_eq!(
+            parse_assignment("SQL_MODE=TRADITIONAL").unwrap().0,
+            "sql_mode"
+        );
+    }
+
+    #[test]
+    fn test_set_statements() {
+        let variables = [
+            (
+                "sql_mode",
+                SessionValue::String("STRICT_ALL_TABLES,NO_ENGINE_SUBSTITUTION".to_string()),
+            ),
+            ("foreign_key_checks", SessionValue::Bool(true)),
+            ("lock_wait_timeout", SessionValue::Integer(10)),
+            ("time_zone", SessionValue::String("it's".to_string())),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            set_statements(&variables).unwrap(),
+            vec![
+                "SET SESSION foreign_key_checks = ON",
+                "SET SESSION lock_wait_timeout = 10",
+                "SET SESSION sql_mode = 'STRICT_ALL_TABLES,NO_ENGINE_SUBSTITUTION'",
+                "SET SESSION time_zone = 'it''s'",
+            ]
+        );
+        for (name, value) in [
+            ("sql_mode; DROP TABLE x", SessionValue::Integer(1)),
+            ("time_zone", SessionValue::String("\\'".to_string())),
+        ] {
+            let variables = BTreeMap::from([(name.to_string(), value)]);
+            assert!(set_statements(&variables).is_err());
+        }
+        let variables =
+            BTreeMap::from([("Foreign_Key_Checks".to_string(), SessionValue::Integer(0))]);
+        assert_eq!(
+            set_statements(&variables).unwrap(),
+            vec!["SET SESSION foreign_key_checks = 0"]
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! A small MySQL-aware lexer, used to split migrations and snapshots into statements without
+//! mangling string literals, quoted identifiers or comments.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Whitespace,
+    /// `-- ...` or `# ...`, not including the trailing newline
+    LineComment,
+    /// `/* ... */`
+    BlockComment,
+    /// `/*! ... */` or `/*+ ... */`; these are interpreted by the server, so they must be kept
+    ExecutableComment,
+    /// `'...'` or `"..."`
+    String,
+    /// `` `...` ``
+    QuotedIdentifier,
+    /// The statement delimiter (`;` unless changed with `DELIMITER`)
+    Delimiter,
+    /// A `DELIMITER xyz` line, as understood by the `mysql` command-line client
+    DelimiterDirective,
+    /// Anything else (keywords, identifiers, numbers, operators, ...)
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub offset: usize,
+}
+
+impl Token<'_> {
+    /// Whether this token has no effect on the meaning of a statement
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self.kind,
+            TokenKind::Whitespace | TokenKind::LineComment | TokenKind::BlockComment
+        )
+    }
+}
+
+/// 1-based line number of the given byte offset
+pub(crate) fn line_of(input: &str, offset: usize) -> usize {
+    input[..offset].matches('\n').count() + 1
+}
+
+const DEFAULT_DELIMITER: &str = ";";
+
+pub(crate) struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+    delimiter: String,
+    // offset of the DELIMITER directive which set the current delimiter, if any
+    delimiter_offset: Option<usize>,
+    // DELIMITER is only recognized as the first thing in a statement
+    at_statement_start: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Lexer {
+            input,
+            pos: 0,
+            delimiter: DEFAULT_DELIMITER.to_string(),
+            delimiter_offset: None,
+            at_statement_start: true,
+        }
+    }
+
+    /// The delimiter currently in effect
+    pub fn delimiter(&self) -> &str {
+        &self.delimiter
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn starts_line_comment(s: &str) -> bool {
+        if s.starts_with('#') {
+            return true;
+        }
+        // MySQL requires `--` to be followed by whitespace (or a control character) to be a comment
+        match s.strip_prefix("--") {
+            Some(after) => after
+                .chars()
+                .next()
+                .is_none_or(|c| c.is_ascii_whitespace() || c.is_ascii_control()),
+            None => false,
+        }
+    }
+
+    fn starts_token(&self, s: &str) -> bool {
+        match s.chars().next() {
+            None => true,
+            Some(c) if c.is_whitespace() => true,
+            Some('\'' | '"' | '`') => true,
+            Some(_) => {
+                s.starts_with(self.delimiter.as_str())
+                    || s.starts_with("/*")
+                    || Self::starts_line_comment(s)
+            }
+        }
+    }
+
+    /// If `s` starts with a `DELIMITER` directive, returns the length of the directive line and
+    /// the new delimiter
+    fn delimiter_directive(&self, s: &'a str) -> anyhow::Result<Option<(usize, &'a str)>> {
+        const KEYWORD: &str = "delimiter";
+        let is_directive = s
+            .get(..KEYWORD.len())
+            .is_some_and(|k| k.eq_ignore_ascii_case(KEYWORD))
+            && s[KEYWORD.len()..]
+                .chars()
+                .next()
+                .is_none_or(|c| c.is_whitespace());
+        if !is_directive {
+            return Ok(None);
+        }
+        let len = s.find('\n').unwrap_or(s.len());
+        let argument = s[KEYWORD.len()..len]
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+        let delimiter = match argument.chars().next() {
+            Some(q @ ('\'' | '"' | '`')) if argument.len() > 1 && argument.ends_with(q) => {
+                &argument[1..argument.len() - 1]
+            }
+            _ => argument,
+        };
+        if delimiter.is_empty() {
+            anyhow::bail!(
+                "DELIMITER on line {} must be followed by a delimiter",
+                line_of(self.input, self.pos)
+            );
+        }
+        if delimiter.contains('\\') {
+            anyhow::bail!(
+                "DELIMITER on line {} may not contain a backslash",
+                line_of(self.input, self.pos)
+            );
+        }
+        Ok(Some((len, delimiter)))
+    }
+
+    /// Length of a quoted string or identifier starting at the beginning of `s`
+    fn quoted_len(s: &str, quote: char) -> Option<usize> {
+        let mut chars = s.char_indices().skip(1).peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '\\' && quote != '`' {
+                chars.next();
+            } else if c == quote {
+                if chars.peek().map(|&(_, n)| n) == Some(quote) {
+                    chars.next();
+                } else {
+                    return Some(i + c.len_utf8());
+                }
+            }
+        }
+        None
+    }
+
+    fn next_token(&mut self) -> anyhow::Result<Option<Token<'a>>> {
+        let rest = self.rest();
+        let Some(first) = rest.chars().next() else {
+            return Ok(None);
+        };
+        let directive = if self.at_statement_start {
+            self.delimiter_directive(rest)?
+        } else {
+            None
+        };
+        let (kind, len) = if let Some((len, delimiter)) = directive {
+            self.delimiter = delimiter.to_string();
+            self.delimiter_offset = Some(self.pos);
+            (TokenKind::DelimiterDirective, len)
+        } else if first.is_whitespace() {
+            let len = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            (TokenKind::Whitespace, len)
+        } else if Self::starts_line_comment(rest) {
+            (
+                TokenKind::LineComment,
+                rest.find('\n').unwrap_or(rest.len()),
+            )
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            let end = body.find("*/").ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unterminated comment starting on line {}",
+                    line_of(self.input, self.pos)
+                )
+            })?;
+            let kind = if body.starts_with('!') || body.starts_with('+') {
+                TokenKind::ExecutableComment
+            } else {
+                TokenKind::BlockComment
+            };
+            (kind, end + 4)
+        } else if matches!(first, '\'' | '"' | '`') {
+            let len = Self::quoted_len(rest, first).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unterminated {} starting on line {}",
+                    if first == '`' { "identifier" } else { "string" },
+                    line_of(self.input, self.pos)
+                )
+            })?;
+            let kind = if first == '`' {
+                TokenKind::QuotedIdentifier
+            } else {
+                TokenKind::String
+            };
+            (kind, len)
+        } else if rest.starts_with(self.delimiter.as_str()) {
+            (TokenKind::Delimiter, self.delimiter.len())
+        } else {
+            let len = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(i, _)| self.starts_token(&rest[i..]))
+                .map_or(rest.len(), |(i, _)| i);
+            (TokenKind::Text, len)
+        };
+        let token = Token {
+            kind,
+            text: &rest[..len],
+            offset: self.pos,
+        };
+        if matches!(kind, TokenKind::Delimiter | TokenKind::DelimiterDirective) {
+            self.at_statement_start = true;
+        } else if !token.is_trivia() {
+            self.at_statement_start = false;
+        }
+        self.pos += len;
+        Ok(Some(token))
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = anyhow::Result<Token<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_token() {
+            Ok(t) => t.map(Ok),
+            Err(e) => {
+                // don't keep returning the same error forever
+                self.pos = self.input.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Remove comments from `input`, leaving version comments (`/*!80000 ... */`) and optimizer
+/// hints (`/*+ ... */`) alone.
+///
+/// Line breaks are preserved, so that line numbers in the output match those in the input.
+pub(crate) fn strip_comments(input: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(input.len());
+    for token in Lexer::new(input) {
+        let token = token?;
+        match token.kind {
+            TokenKind::LineComment | TokenKind::BlockComment => {
+                out.truncate(out.trim_end_matches([' ', '\t']).len());
+                let newlines = token.text.matches('\n').count();
+                let followed_by_space = input[token.offset + token.text.len()..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace);
+                if newlines > 0 {
+                    out.extend(std::iter::repeat_n('\n', newlines));
+                } else if !followed_by_space && !out.is_empty() && !out.ends_with('\n') {
+                    // don't glue the tokens on either side of the comment together
+                    out.push(' ');
+                }
+            }
+            _ => out.push_str(token.text),
+        }
+    }
+    out.truncate(out.trim_end().len());
+    Ok(out)
+}
+
+/// A single statement, as it appeared in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Statement<'a> {
+    pub text: &'a str,
+    pub line: usize,
+}
+
+/// Split `input` into statements on `;`, the way the `mysql` command-line client does.
+///
+/// Leading and trailing whitespace and comments are dropped from each statement; everything
+/// between them is returned byte-for-byte. Statements consisting only of comments are skipped.
+///
+/// `DELIMITER xyz` lines change the delimiter for the statements that follow them, so that
+/// stored programs containing `;` can be written; the delimiter must be reset to `;` by the end
+/// of the input.
+pub(crate) fn split_statements(input: &str) -> anyhow::Result<Vec<Statement<'_>>> {
+    let mut statements = vec![];
+    let mut span: Option<(usize, usize)> = None;
+    let mut push = |span: Option<(usize, usize)>| {
+        if let Some((start, end)) = span {
+            statements.push(Statement {
+                text: &input[start..end],
+                line: line_of(input, start),
+            });
+        }
+    };
+    let mut lexer = Lexer::new(input);
+    for token in lexer.by_ref() {
+        let token = token?;
+        match token.kind {
+            TokenKind::Delimiter => push(span.take()),
+            TokenKind::DelimiterDirective => {}
+            _ if token.is_trivia() => {}
+            _ => {
+                let end = token.offset + token.text.len();
+                span = Some(span.map_or((token.offset, end), |(start, _)| (start, end)));
+            }
+        }
+    }
+    if lexer.delimiter() != DEFAULT_DELIMITER {
+        let directive_line = line_of(input, lexer.delimiter_offset.unwrap_or(0));
+        if let Some((start, _)) = span {
+            anyhow::bail!(
+                "statement on line {} is not terminated by {:?} (set by DELIMITER on line {})",
+                line_of(input, start),
+                lexer.delimiter(),
+                directive_line,
+            );
+        }
+        anyhow::bail!(
+            "DELIMITER {:?} on line {} is never reset with DELIMITER ;",
+            lexer.delimiter(),
+            directive_line,
+        );
+    }
+    push(span);
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, Statement, TokenKind, split_statements, strip_comments};
+
+    fn texts(input: &str) -> Vec<&str> {
+        split_statements(input)
+            .expect("should split")
+            .into_iter()
+            .map(|s| s.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_lexer_kinds() {
+        let kinds = Lexer::new("SELECT 'a;b' -- hi\n/*! x */`c`;")
+            .map(|t| t.unwrap().kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Text,
+                TokenKind::Whitespace,
+                TokenKind::String,
+                TokenKind::Whitespace,
+                TokenKind::LineComment,
+                TokenKind::Whitespace,
+                TokenKind::ExecutableComment,
+                TokenKind::QuotedIdentifier,
+                TokenKind::Delimiter,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_simple() {
+        assert_eq!(
+            texts("CREATE TABLE a(id INT);\nCREATE TABLE b(id INT);\n"),
+            vec!["CREATE TABLE a(id INT)", "CREATE TABLE b(id INT)"]
+        );
+        assert_eq!(texts("SELECT 1"), vec!["SELECT 1"]);
+        assert_eq!(texts("SELECT 1; SELECT 2;;"), vec!["SELECT 1", "SELECT 2"]);
+        assert!(texts("  \n-- just a comment\n/* and another */\n").is_empty());
+    }
+
+    #[test]
+    fn test_split_keeps_original_bytes() {
+        let input = "INSERT INTO t(v) VALUES ('a;\nb', \"c\\\";\", 'it''s;');\nALTER TABLE `we;ird` COMMENT 'x;\n';";
+        assert_eq!(
+            texts(input),
+            vec![
+                "INSERT INTO t(v) VALUES ('a;\nb', \"c\\\";\", 'it''s;')",
+                "ALTER TABLE `we;ird` COMMENT 'x;\n'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_comments() {
+        assert_eq!(
+            texts("-- leading; comment\nSELECT 1 # trailing; comment\n;\nSELECT /* inner; */ 2;"),
+            vec!["SELECT 1", "SELECT /* inner; */ 2"]
+        );
+        assert_eq!(
+            texts("/*!40101 SET NAMES utf8 */;\nSELECT 3--1;"),
+            vec!["/*!40101 SET NAMES utf8 */", "SELECT 3--1"]
+        );
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let input = "/* rmmm migration v1 - test */\n\
+            -- a comment\n\
+            #another comment\n\
+            CREATE TABLE t( -- trailing\n\
+            \tid INT,--\ttab\n\
+            \tv VARCHAR(10) DEFAULT '-- not a comment' /* multi\nline */\n\
+            ) COMMENT 'it''s /* not */ # a comment';\n\
+            SELECT/* glue */1, 2--1, \"#\";\n\
+            SELECT /*+ MAX_EXECUTION_TIME(1000) */ 1;\n\
+            /*!80000 SET @x = 1 */;\n";
+        assert_eq!(
+            strip_comments(input).unwrap(),
+            "\n\n\n\
+            CREATE TABLE t(\n\
+            \tid INT,\n\
+            \tv VARCHAR(10) DEFAULT '-- not a comment'\n\n\
+            ) COMMENT 'it''s /* not */ # a comment';\n\
+            SELECT 1, 2--1, \"#\";\n\
+            SELECT /*+ MAX_EXECUTION_TIME(1000) */ 1;\n\
+            /*!80000 SET @x = 1 */;"
+        );
+        assert_eq!(
+            strip_comments("SELECT 1;\n--\n-- end").unwrap(),
+            "SELECT 1;"
+        );
+    }
+
+    #[test]
+    fn test_split_lines() {
+        let statements = split_statements("SELECT 1;\n\n/* x */ SELECT\n2;").unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                Statement {
+                    text: "SELECT 1",
+                    line: 1
+                },
+                Statement {
+                    text: "SELECT\n2",
+                    line: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_delimiter() {
+        let input = "CREATE TABLE t(id INT);\n\
+            DELIMITER $$\n\
+            CREATE TRIGGER t_bi BEFORE INSERT ON t FOR EACH ROW\n\
+            BEGIN\n  SET NEW.id = NEW.id + 1;\nEND$$\n\
+            CREATE PROCEDURE p() BEGIN SELECT '$$;'; END $$\n\
+            delimiter ;\n\
+            SELECT 1;\n";
+        assert_eq!(
+            texts(input),
+            vec![
+                "CREATE TABLE t(id INT)",
+                "CREATE TRIGGER t_bi BEFORE INSERT ON t FOR EACH ROW\nBEGIN\n  SET NEW.id = NEW.id + 1;\nEND",
+                "CREATE PROCEDURE p() BEGIN SELECT '$$;'; END",
+                "SELECT 1",
+            ]
+        );
+        assert_eq!(
+            texts("DELIMITER //\nSELECT 1//SELECT 2 //\nDELIMITER ';'\nSELECT 3"),
+            vec!["SELECT 1", "SELECT 2", "SELECT 3"]
+        );
+        // DELIMITER is only a directive at the start of a statement
+        assert_eq!(
+            texts("SELECT delimiter FROM t;"),
+            vec!["SELECT delimiter FROM t"]
+        );
+    }
+
+    #[test]
+    fn test_split_delimiter_errors() {
+        let err = split_statements("DELIMITER $$\nCREATE PROCEDURE p() BEGIN SELECT 1; END")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2 is not terminated"), "{err}");
+        let err = split_statements("DELIMITER $$\nCREATE PROCEDURE p() BEGIN SELECT 1; END$$\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("never reset"), "{err}");
+        assert!(split_statements("DELIMITER\nSELECT 1;").is_err());
+        assert!(split_statements("DELIMITER \\\\\nSELECT 1;").is_err());
+    }
+
+    #[test]
+    fn test_split_unterminated() {
+        assert!(split_statements("SELECT 'abc;").is_err());
+        assert!(split_statements("SELECT `abc;").is_err());
+        assert!(split_statements("SELECT 1; /* abc").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A possibly schema-qualified table name, such as `rmmm_migrations` or `meta.app_migrations`.
+///
+/// Displays as a quoted identifier, ready to be interpolated into SQL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TableName {
+    pub schema: Option<String>,
+    pub name: String,
+}
+
+impl TableName {
+    /// A table with the given name in the same schema as this one
+    pub fn sibling(&self, name: &str) -> Self {
+        TableName {
+            schema: self.schema.clone(),
+            name: name.to_string(),
+        }
+    }
+}
+
+/// Quote an identifier with backticks
+pub(crate) fn quote_identifier(s: &str) -> String {
+    format!("`{}`", s.replace('`', "``"))
+}
+
+impl fmt::Display for TableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(schema) = &self.schema {
+            write!(f, "{}.", quote_identifier(schema))?;
+        }
+        write!(f, "{}", quote_identifier(&self.name))
+    }
+}
+
+impl FromStr for TableName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut current = String::new();
+        let mut chars = s.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '`' if quoted && chars.peek() == Some(&'`') => {
+                    chars.next();
+                    current.push('`');
+                }
+                '`' => quoted = !quoted,
+                '.' if !quoted => parts.push(std::mem::take(&mut current)),
+                c => current.push(c),
+            }
+        }
+        if quoted {
+            anyhow::bail!("unterminated quote in table name {}", s);
+        }
+        parts.push(current);
+        if parts.iter().any(|p| p.is_empty()) {
+            anyhow::bail!("invalid table name {:?}", s);
+        }
+        match parts.as_mut_slice() {
+            [name] => Ok(TableName {
+                schema: None,
+                name: std::mem::take(name),
+            }),
+            [schema, name] => Ok(TableName {
+                schema: Some(std::mem::take(schema)),
+                name: std::mem::take(name),
+            }),
+            _ => anyhow::bail!("table name {:?} has too many parts", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TableName;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "rmmm_migrations".parse::<TableName>().unwrap(),
+            TableName {
+                schema: None,
+                name: "rmmm_migrations".to_string()
+            }
+        );
+        assert_eq!(
+            "meta.app_migrations".parse::<TableName>().unwrap(),
+            TableName {
+                schema: Some("meta".to_string()),
+                name: "app_migrations".to_string()
+            }
+        );
+        assert_eq!(
+            "`my.schema`.`we``ird`".parse::<TableName>().unwrap(),
+            TableName {
+                schema: Some("my.schema".to_string()),
+                name: "we`ird".to_string()
+            }
+        );
+        for s in &["", "a.", ".b", "a.b.c", "`a"] {
+            assert!(s.parse::<TableName>().is_err(), "{s} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            "meta.app_migrations"
+                .parse::<TableName>()
+                .unwrap()
+                .to_string(),
+            "`meta`.`app_migrations`"
+        );
+        assert_eq!(
+            TableName {
+                schema: None,
+                name: "we`ird".to_string()
+            }
+            .to_string(),
+            "`we``ird`"
+        );
+        assert_eq!(
+            "meta.app_migrations"
+                .parse::<TableName>()
+                .unwrap()
+                .sibling("log")
+                .to_string(),
+            "`meta`.`log`"
+        );
+    }
+}
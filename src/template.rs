@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use crate::migration_state::split_sections;
+
+/// Templates live in this directory under the migration path
+pub(crate) const TEMPLATE_DIR: &str = "templates";
+
+static VARIABLE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap());
+
+/// A template for new migrations: `db/templates/{name}.sql`, plus `{name}.down.sql` if the
+/// template has a downgrade. A template with `-- +up` / `-- +down` sections has both in one file.
+#[derive(Debug)]
+pub(crate) struct Template {
+    pub name: String,
+    upgrade: String,
+    downgrade: Option<String>,
+}
+
+impl Template {
+    pub fn load(root_path: &Path, name: &str) -> anyhow::Result<Self> {
+        let dir = root_path.join(TEMPLATE_DIR);
+        let path = dir.join(format!("{name}.sql"));
+        if !path.is_file() {
+            anyhow::bail!(
+                "no template {:?} in {}; available templates are {:?}",
+                name,
+                dir.display(),
+                Self::available(&dir)
+            );
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let (upgrade, downgrade) = match split_sections(&text)
+            .with_context(|| format!("Could not parse {}", path.display()))?
+        {
+            Some((up, down)) => (up, Some(down)),
+            None => {
+                let down_path = dir.join(format!("{name}.down.sql"));
+                let downgrade = if down_path.is_file() {
+                    Some(
+                        std::fs::read_to_string(&down_path)
+                            .with_context(|| format!("Could not read {}", down_path.display()))?,
+                    )
+                } else {
+                    None
+                };
+                (text, downgrade)
+            }
+        };
+        Ok(Template {
+            name: name.to_string(),
+            upgrade: upgrade.trim().to_string(),
+            downgrade: downgrade
+                .map(|d| d.trim().to_string())
+                .filter(|d| !d.is_empty()),
+        })
+    }
+
+    fn available(dir: &Path) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return vec![];
+        };
+        let mut names = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(str::to_owned))
+            .filter(|name| !name.ends_with(".down.sql"))
+            .filter_map(|name| name.strip_suffix(".sql").map(str::to_owned))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Fill in `{{name}}` variables, returning the upgrade and downgrade
+    pub fn render(
+        &self,
+        variables: &BTreeMap<String, String>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let mut missing = BTreeSet::new();
+        let mut render = |text: &str| {
+            VARIABLE_REGEX
+                .replace_all(text, |caps: &Captures| match variables.get(&caps[1]) {
+                    Some(value) => value.clone(),
+                    None => {
+                        missing.insert(caps[1].to_string());
+                        String::new()
+                    }
+                })
+                .into_owned()
+        };
+        let upgrade = render(&self.upgrade);
+        let downgrade = self.downgrade.as_deref().map(render);
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "template {} needs {}",
+                self.name,
+                missing
+                    .iter()
+                    .map(|name| format!("--var {name}=VALUE"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok((upgrade, downgrade))
+    }
+}
+
+/// Parse a `--var NAME=VALUE` argument
+pub(crate) fn parse_variable(s: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("template variable {:?} must look like NAME=VALUE", s))?;
+    Ok((name.trim().to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{TEMPLATE_DIR, Template, parse_variable};
+
+    #[test]
+    fn test_load_and_render() {
+        let wd = tempfile::TempDir::new().unwrap();
+        let dir = wd.path().join(TEMPLATE_DIR);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("add_index.sql"),
+            "ALTER TABLE {{table}} ADD INDEX idx_{{ column }} ({{column}}), ALGORITHM=INPLACE, LOCK=NONE;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("add_index.down.sql"),
+            "ALTER TABLE {{table}} DROP INDEX idx_{{column}};\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("create_table.sql"),
+            "-- +up\nCREATE TABLE {{table}}(id BIGINT PRIMARY KEY) COMMENT '{{label}}';\n-- +down\nDROP TABLE {{table}};\n",
+        )
+        .unwrap();
+
+        let variables = BTreeMap::from([
+            ("table".to_string(), "orders".to_string()),
+            ("column".to_string(), "created_at".to_string()),
+            ("label".to_string(), "add orders".to_string()),
+        ]);
+        let (up, down) = Template::load(wd.path(), "add_index")
+            .unwrap()
+            .render(&variables)
+            .unwrap();
+        assert_eq!(
+            up,
+            "ALTER TABLE orders ADD INDEX idx_created_at (created_at), ALGORITHM=INPLACE, LOCK=NONE;"
+        );
+        assert_eq!(
+            down.as_deref(),
+            Some("ALTER TABLE orders DROP INDEX idx_created_at;")
+        );
+
+        let (up, down) = Template::load(wd.path(), "create_table")
+            .unwrap()
+            .render(&variables)
+            .unwrap();
+        assert_eq!(
+            up,
+            "CREATE TABLE orders(id BIGINT PRIMARY KEY) COMMENT 'add orders';"
+        );
+        assert_eq!(down.as_deref(), Some("DROP TABLE orders;"));
+
+        let err = Template::load(wd.path(), "add_index")
+            .unwrap()
+            .render(&BTreeMap::new())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template add_index needs --var column=VALUE, --var table=VALUE"
+        );
+        let err = Template::load(wd.path(), "add_column").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(r#"available templates are ["add_index", "create_table"]"#),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_variable() {
+        assert_eq!(
+            parse_variable("table=orders").unwrap(),
+            ("table".to_string(), "orders".to_string())
+        );
+        assert!(parse_variable("table").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+/// `generate` names timestamped migrations after the UTC time, like `v20261016183000`
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// The smallest version which is taken to be a timestamp; nobody has 10 trillion sequential
+/// migrations
+const MIN_TIMESTAMP: u64 = 10_000_000_000_000;
+
+/// The version of a migration. Versions are either sequential (1, 2, 3, ...) or timestamps
+/// (20261016183000), and timestamps always sort after sequential versions, which is what lets
+/// a project switch from one to the other.
+#[derive(Display, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub(crate) struct Version(pub u64);
+
+// lists of versions show up in error messages, where [3, 4] reads better than
+// [Version(3), Version(4)]
+impl fmt::Debug for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Version {
+    pub fn is_timestamp(self) -> bool {
+        self.0 >= MIN_TIMESTAMP
+    }
+
+    /// The version for a new migration, given the highest existing one. Timestamps are bumped
+    /// past `self` if need be, so versions still increase when the clock goes backwards or two
+    /// migrations are generated in the same second.
+    pub fn next(self, versioning: Versioning, now: DateTime<Utc>) -> Version {
+        let next = Version(self.0 + 1);
+        match versioning {
+            Versioning::Sequential => next,
+            Versioning::Timestamp => {
+                let timestamp = now.format(TIMESTAMP_FORMAT).to_string().parse().unwrap();
+                next.max(Version(timestamp))
+            }
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    /// Accepts `42` as well as `v42`, as migration files are named
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix('v')
+            .unwrap_or(s)
+            .parse()
+            .map(Version)
+            .map_err(|_| anyhow::anyhow!("invalid migration version {:?}", s))
+    }
+}
+
+impl From<Version> for mysql::Value {
+    fn from(version: Version) -> Self {
+        mysql::Value::UInt(version.0)
+    }
+}
+
+/// How `generate` picks the version of a new migration, set with `versioning` in rmmm.toml
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Versioning {
+    /// 1, 2, 3, ...
+    #[default]
+    Sequential,
+    /// The UTC time the migration was generated, which branches can't both pick
+    Timestamp,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::{Version, Versioning};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("42".parse::<Version>().unwrap(), Version(42));
+        assert_eq!("v42".parse::<Version>().unwrap(), Version(42));
+        assert!("latest".parse::<Version>().is_err());
+        assert!(!Version(42).is_timestamp());
+        assert!(Version(20261016183000).is_timestamp());
+    }
+
+    #[test]
+    fn test_next() {
+        let now = chrono::Utc
+            .with_ymd_and_hms(2026, 10, 16, 18, 30, 0)
+            .unwrap();
+        assert_eq!(Version(41).next(Versioning::Sequential, now), Version(42));
+        assert_eq!(
+            Version(41).next(Versioning::Timestamp, now),
+            Version(20261016183000)
+        );
+        // generated twice in the same second
+        assert_eq!(
+            Version(20261016183000).next(Versioning::Timestamp, now),
+            Version(20261016183001)
+        );
+    }
+}